@@ -0,0 +1,121 @@
+// Adjustments run on the finished pallet before it is written, to make the colors of an image more
+// vivid, lighter, warmer and so on. They work in OKLCH so a change looks the same size on every color,
+// and run one after the other in the order given. Counts are kept.
+use crate::color::Color;
+use crate::q_image::ColorChannel;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Adjustment {
+    // Chroma changed by this percent, negative desaturates and -100 leaves greys
+    Saturate(f64),
+    // OKLab lightness changed by this many hundredths, negative darkens
+    Lighten(f64),
+    // Lightness spread out from the pallet's mean lightness by this percent, negative flattens
+    Contrast(f64),
+    // Hue turned by this many degrees
+    HueRotate(f64),
+    // Colors moved towards orange by up to WARMEST at 100, negative moves them towards blue
+    Temperature(f64),
+}
+
+// Names accepted by Adjustment::from_name(), also the command line flags
+pub const ADJUSTMENTS: [&str; 7] = [
+    "saturate",
+    "desaturate",
+    "lighten",
+    "darken",
+    "contrast",
+    "hue-rotate",
+    "temperature",
+];
+
+// How far in OKLab a temperature of 100 moves the colors, and which way (OKLCH hue of orange)
+const WARMEST: f64 = 0.04;
+const WARM_HUE: f64 = 60.0;
+
+impl Adjustment {
+    pub fn from_name(name: &str, amount: f64) -> Option<Adjustment> {
+        match name {
+            "saturate" => Some(Adjustment::Saturate(amount)),
+            "desaturate" => Some(Adjustment::Saturate(-amount)),
+            "lighten" => Some(Adjustment::Lighten(amount)),
+            "darken" => Some(Adjustment::Lighten(-amount)),
+            "contrast" => Some(Adjustment::Contrast(amount)),
+            "hue-rotate" => Some(Adjustment::HueRotate(amount)),
+            "temperature" => Some(Adjustment::Temperature(amount)),
+            _ => None,
+        }
+    }
+
+    fn apply(self, pallet: &mut [ColorChannel]) {
+        let mean_l = if pallet.is_empty() {
+            0.0
+        } else {
+            pallet.iter().map(|c| c.color().to_oklch()[0]).sum::<f64>() / pallet.len() as f64
+        };
+        for c in pallet.iter_mut() {
+            let [l, chroma, hue] = c.color().to_oklch();
+            let adjusted = match self {
+                Adjustment::Saturate(p) => [l, chroma * (1.0 + p / 100.0).max(0.0), hue],
+                Adjustment::Lighten(p) => [l + p / 100.0, chroma, hue],
+                Adjustment::Contrast(p) => [mean_l + (l - mean_l) * (1.0 + p / 100.0), chroma, hue],
+                Adjustment::HueRotate(degrees) => [l, chroma, hue + degrees],
+                Adjustment::Temperature(p) => {
+                    let shift = WARMEST * p / 100.0;
+                    let warm = WARM_HUE.to_radians();
+                    let a = chroma * hue.to_radians().cos() + shift * warm.cos();
+                    let b = chroma * hue.to_radians().sin() + shift * warm.sin();
+                    [l, a.hypot(b), b.atan2(a).to_degrees()]
+                }
+            };
+            let [l, chroma, hue] = adjusted;
+            let color = Color::from_oklch_in_gamut([l.clamp(0.0, 1.0), chroma, hue]);
+            *c = ColorChannel::from_color(color, c.count);
+        }
+    }
+}
+
+// Runs every adjustment on the pallet, in order
+pub fn adjust_pallet(pallet: &mut [ColorChannel], adjustments: &[Adjustment]) {
+    for adjustment in adjustments {
+        adjustment.apply(pallet);
+    }
+}
+
+// Reads adjustments from a config file, one per line as a name and an amount:
+//
+//     # warmer and a bit more vivid
+//     saturate 20
+//     temperature 30
+//
+// desaturate and darken can be used for negative saturate and lighten. Amounts have to be finite
+// numbers. Blank lines and lines starting with # are skipped.
+pub fn parse_adjustments(text: &str) -> Result<Vec<Adjustment>, String> {
+    let mut adjustments = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let adjustment = match words[..] {
+            [name, amount] => amount
+                .parse::<f64>()
+                .ok()
+                .filter(|amount| amount.is_finite())
+                .and_then(|amount| Adjustment::from_name(name, amount)),
+            _ => None,
+        };
+        match adjustment {
+            Some(a) => adjustments.push(a),
+            None => {
+                return Err(format!(
+                    "line {}: expected <adjustment> <amount>, found \"{}\"",
+                    n + 1,
+                    line
+                ))
+            }
+        }
+    }
+    Ok(adjustments)
+}
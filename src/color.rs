@@ -0,0 +1,377 @@
+// Conversions between sRGB, linear RGB, HSL, HSV and the perceptual color spaces used while
+// quantizing. All of them use the D65 white point. The rgb_to_*/*_to_rgb functions take and return
+// 0-255 channels, Color keeps the full precision in between.
+
+// A color as sRGB channels from 0.0 to 1.0. Nothing is clamped or rounded until it is turned back
+// into bytes with to_rgb8(), so converting to another space and back gives the same color, and colors
+// pushed out of gamut by an adjustment can still be brought back.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Color {
+    pub red: f64,
+    pub grn: f64,
+    pub blu: f64,
+}
+
+// Undo the sRGB transfer curve, mirrored for negative values so out of gamut colors round trip
+fn decode(c: f64) -> f64 {
+    if c.abs() <= 0.04045 {
+        c / 12.92
+    } else {
+        c.signum() * ((c.abs() + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn encode(c: f64) -> f64 {
+    if c.abs() <= 0.003_130_8 {
+        12.92 * c
+    } else {
+        c.signum() * (1.055 * c.abs().powf(1.0 / 2.4) - 0.055)
+    }
+}
+
+// D65 reference white used by CIELAB
+const WHITE_X: f64 = 0.950_47;
+const WHITE_Y: f64 = 1.0;
+const WHITE_Z: f64 = 1.088_83;
+
+fn lab_f(t: f64) -> f64 {
+    if t > 216.0 / 24389.0 {
+        t.cbrt()
+    } else {
+        (24389.0 / 27.0 * t + 16.0) / 116.0
+    }
+}
+
+fn lab_f_inv(t: f64) -> f64 {
+    if t * t * t > 216.0 / 24389.0 {
+        t * t * t
+    } else {
+        (116.0 * t - 16.0) / (24389.0 / 27.0)
+    }
+}
+
+// Hue in degrees from 0 up to 360
+fn hue_degrees(y: f64, x: f64) -> f64 {
+    y.atan2(x).to_degrees().rem_euclid(360.0)
+}
+
+impl Color {
+    pub fn new(red: f64, grn: f64, blu: f64) -> Color {
+        Color { red, grn, blu }
+    }
+
+    pub fn from_rgb8(red: u8, grn: u8, blu: u8) -> Color {
+        Color::new(red as f64 / 255.0, grn as f64 / 255.0, blu as f64 / 255.0)
+    }
+
+    // Rounded and clamped to 0-255
+    pub fn to_rgb8(self) -> [u8; 3] {
+        let byte = |c: f64| (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+        [byte(self.red), byte(self.grn), byte(self.blu)]
+    }
+
+    // Whether the color can be shown without clamping, allowing for rounding errors
+    pub fn in_gamut(self) -> bool {
+        [self.red, self.grn, self.blu]
+            .iter()
+            .all(|c| (-1e-9..=1.0 + 1e-9).contains(c))
+    }
+
+    // Linear light, 0.0-1.0 per channel
+    pub fn from_linear(rgb: [f64; 3]) -> Color {
+        Color::new(encode(rgb[0]), encode(rgb[1]), encode(rgb[2]))
+    }
+
+    pub fn to_linear(self) -> [f64; 3] {
+        [decode(self.red), decode(self.grn), decode(self.blu)]
+    }
+
+    // CIELAB, L 0-100 with a/b roughly -128 to 127
+    pub fn from_lab(lab: [f64; 3]) -> Color {
+        let fy = (lab[0] + 16.0) / 116.0;
+        let fx = fy + lab[1] / 500.0;
+        let fz = fy - lab[2] / 200.0;
+        let x = lab_f_inv(fx) * WHITE_X;
+        let y = lab_f_inv(fy) * WHITE_Y;
+        let z = lab_f_inv(fz) * WHITE_Z;
+
+        Color::from_linear([
+            3.240_454_162_114_105_4 * x
+                - 1.537_138_512_797_716_6 * y
+                - 0.498_531_409_556_016_27 * z,
+            -0.969_266_030_505_186_7 * x
+                + 1.876_010_845_446_694 * y
+                + 0.041_556_017_530_349_834 * z,
+            0.055_643_430_959_114_69 * x - 0.204_025_913_516_753_87 * y
+                + 1.057_225_188_223_179_1 * z,
+        ])
+    }
+
+    pub fn to_lab(self) -> [f64; 3] {
+        let [r, g, b] = self.to_linear();
+        let x =
+            0.412_456_439_089_692_1 * r + 0.357_576_077_643_909 * g + 0.180_437_483_266_398_94 * b;
+        let y =
+            0.212_672_851_405_622_48 * r + 0.715_152_155_287_818 * g + 0.072_174_993_306_559_58 * b;
+        let z =
+            0.019_333_895_582_329_317 * r + 0.119_192_025_881_303 * g + 0.950_304_078_536_367_7 * b;
+
+        let fx = lab_f(x / WHITE_X);
+        let fy = lab_f(y / WHITE_Y);
+        let fz = lab_f(z / WHITE_Z);
+        [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+    }
+
+    // OKLab by Björn Ottosson, https://bottosson.github.io/posts/oklab/
+    // L 0-1 with a/b roughly -0.4 to 0.4. The inverse matrices are worked out from the published
+    // forward ones rather than copied, so converting there and back doesn't drift.
+    pub fn from_oklab(lab: [f64; 3]) -> Color {
+        let l = 0.999_999_998_450_519_9 * lab[0]
+            + 0.396_337_792_173_767_86 * lab[1]
+            + 0.215_803_758_060_758_77 * lab[2];
+        let m = 1.000_000_008_881_760_7 * lab[0]
+            - 0.105_561_342_323_656_34 * lab[1]
+            - 0.063_854_174_771_705_9 * lab[2];
+        let s = 1.000_000_054_672_410_8 * lab[0]
+            - 0.089_484_182_094_965_75 * lab[1]
+            - 1.291_485_537_864_091_7 * lab[2];
+        let (l, m, s) = (l * l * l, m * m * m, s * s * s);
+
+        Color::from_linear([
+            4.076_741_661_347_994 * l - 3.307_711_590_408_193_7 * m + 0.230_969_928_729_427_9 * s,
+            -1.268_438_004_092_176_3 * l + 2.609_757_400_663_372 * m - 0.341_319_396_310_219_6 * s,
+            -0.004_196_086_541_837_087 * l - 0.703_418_614_459_449_5 * m
+                + 1.707_614_700_930_944_8 * s,
+        ])
+    }
+
+    pub fn to_oklab(self) -> [f64; 3] {
+        let [r, g, b] = self.to_linear();
+        let l = (0.412_221_470_8 * r + 0.536_332_536_3 * g + 0.051_445_992_9 * b).cbrt();
+        let m = (0.211_903_498_2 * r + 0.680_699_545_1 * g + 0.107_396_956_6 * b).cbrt();
+        let s = (0.088_302_461_9 * r + 0.281_718_837_6 * g + 0.629_978_700_5 * b).cbrt();
+
+        [
+            0.210_454_255_3 * l + 0.793_617_785_0 * m - 0.004_072_046_8 * s,
+            1.977_998_495_1 * l - 2.428_592_205_0 * m + 0.450_593_709_9 * s,
+            0.025_904_037_1 * l + 0.782_771_766_2 * m - 0.808_675_766_0 * s,
+        ]
+    }
+
+    // OKLab in polar form, L 0-1, chroma 0 to roughly 0.4 and hue in degrees
+    pub fn from_oklch(lch: [f64; 3]) -> Color {
+        let h = lch[2].to_radians();
+        Color::from_oklab([lch[0], lch[1] * h.cos(), lch[1] * h.sin()])
+    }
+
+    pub fn to_oklch(self) -> [f64; 3] {
+        let [l, a, b] = self.to_oklab();
+        [l, a.hypot(b), hue_degrees(b, a)]
+    }
+
+    // The OKLCH color with its chroma lowered until it fits in sRGB, lightness and hue are kept
+    pub fn from_oklch_in_gamut(lch: [f64; 3]) -> Color {
+        let color = Color::from_oklch(lch);
+        if color.in_gamut() {
+            return color;
+        }
+        let (mut low, mut high) = (0.0, lch[1]);
+        for _ in 0..24 {
+            let mid = (low + high) / 2.0;
+            if Color::from_oklch([lch[0], mid, lch[2]]).in_gamut() {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        Color::from_oklch([lch[0], low, lch[2]])
+    }
+}
+
+// HSL and HSV are cylinders around the sRGB cube, hue in degrees with saturation, lightness and
+// value from 0.0 to 1.0. Greys get a hue of 0. Used for the hsl() and hsv() colors templates and
+// the command line read and write.
+impl Color {
+    // Hue and chroma shared by HSL and HSV, along with the largest and smallest channel
+    fn hue_chroma(self) -> (f64, f64, f64, f64) {
+        let max = self.red.max(self.grn).max(self.blu);
+        let min = self.red.min(self.grn).min(self.blu);
+        let chroma = max - min;
+        let hue = if chroma == 0.0 {
+            0.0
+        } else if max == self.red {
+            60.0 * ((self.grn - self.blu) / chroma).rem_euclid(6.0)
+        } else if max == self.grn {
+            60.0 * ((self.blu - self.red) / chroma + 2.0)
+        } else {
+            60.0 * ((self.red - self.grn) / chroma + 4.0)
+        };
+        (hue, chroma, max, min)
+    }
+
+    // The sRGB color with this hue and chroma, offset so the smallest channel is m
+    fn from_hue_chroma(hue: f64, chroma: f64, m: f64) -> Color {
+        let h = hue.rem_euclid(360.0) / 60.0;
+        let x = chroma * (1.0 - (h.rem_euclid(2.0) - 1.0).abs());
+        let (r, g, b) = match h as u32 {
+            0 => (chroma, x, 0.0),
+            1 => (x, chroma, 0.0),
+            2 => (0.0, chroma, x),
+            3 => (0.0, x, chroma),
+            4 => (x, 0.0, chroma),
+            _ => (chroma, 0.0, x),
+        };
+        Color::new(r + m, g + m, b + m)
+    }
+
+    pub fn from_hsl(hsl: [f64; 3]) -> Color {
+        let chroma = (1.0 - (2.0 * hsl[2] - 1.0).abs()) * hsl[1];
+        Color::from_hue_chroma(hsl[0], chroma, hsl[2] - chroma / 2.0)
+    }
+
+    pub fn to_hsl(self) -> [f64; 3] {
+        let (hue, chroma, max, min) = self.hue_chroma();
+        let l = (max + min) / 2.0;
+        let s = if chroma == 0.0 {
+            0.0
+        } else {
+            chroma / (1.0 - (2.0 * l - 1.0).abs())
+        };
+        [hue, s, l]
+    }
+
+    pub fn from_hsv(hsv: [f64; 3]) -> Color {
+        let chroma = hsv[2] * hsv[1];
+        Color::from_hue_chroma(hsv[0], chroma, hsv[2] - chroma)
+    }
+
+    pub fn to_hsv(self) -> [f64; 3] {
+        let (hue, chroma, max, _) = self.hue_chroma();
+        let s = if max == 0.0 { 0.0 } else { chroma / max };
+        [hue, s, max]
+    }
+}
+
+// How bright a color is, used to pick the background and foreground. Every model gives 0.0 for black
+// and 1.0 for white.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Luminance {
+    // WCAG 2 relative luminance, Rec.709 weights on linear sRGB
+    #[default]
+    Wcag,
+    // The same weights on channels linearized with the Rec.709 camera curve instead of sRGB's
+    Rec709,
+    // OKLab L, perceived lightness
+    Oklab,
+    // CIELAB L* scaled to 0-1
+    Lab,
+}
+
+// Names accepted by Luminance::from_name(), used for the --luminance option
+pub const LUMINANCE_MODELS: [&str; 4] = ["wcag", "rec709", "oklab", "lab"];
+
+impl Luminance {
+    pub fn from_name(name: &str) -> Option<Luminance> {
+        match name {
+            "wcag" => Some(Luminance::Wcag),
+            "rec709" => Some(Luminance::Rec709),
+            "oklab" => Some(Luminance::Oklab),
+            "lab" => Some(Luminance::Lab),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Luminance::Wcag => "wcag",
+            Luminance::Rec709 => "rec709",
+            Luminance::Oklab => "oklab",
+            Luminance::Lab => "lab",
+        }
+    }
+
+    pub fn of(self, color: Color) -> f64 {
+        let weigh = |[r, g, b]: [f64; 3]| 0.2126 * r + 0.7152 * g + 0.0722 * b;
+        match self {
+            Luminance::Wcag => weigh(color.to_linear()),
+            Luminance::Rec709 => {
+                let decode = |v: f64| {
+                    if v < 0.081 {
+                        v / 4.5
+                    } else {
+                        ((v + 0.099) / 1.099).powf(1.0 / 0.45)
+                    }
+                };
+                weigh([decode(color.red), decode(color.grn), decode(color.blu)])
+            }
+            Luminance::Oklab => color.to_oklab()[0],
+            Luminance::Lab => color.to_lab()[0] / 100.0,
+        }
+    }
+}
+
+pub fn rgb_to_lab(red: u8, grn: u8, blu: u8) -> [f64; 3] {
+    Color::from_rgb8(red, grn, blu).to_lab()
+}
+
+pub fn lab_to_rgb(lab: [f64; 3]) -> [u8; 3] {
+    Color::from_lab(lab).to_rgb8()
+}
+
+pub fn rgb_to_oklab(red: u8, grn: u8, blu: u8) -> [f64; 3] {
+    Color::from_rgb8(red, grn, blu).to_oklab()
+}
+
+pub fn oklab_to_rgb(lab: [f64; 3]) -> [u8; 3] {
+    Color::from_oklab(lab).to_rgb8()
+}
+
+// Color space the median cut buckets are measured, split and averaged in
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ColorSpace {
+    #[default]
+    Rgb,
+    Lab,
+    Oklab,
+}
+
+// Names accepted by ColorSpace::from_name(), used for the --space option
+pub const COLOR_SPACES: [&str; 3] = ["rgb", "lab", "oklab"];
+
+impl ColorSpace {
+    pub fn from_name(name: &str) -> Option<ColorSpace> {
+        match name {
+            "rgb" => Some(ColorSpace::Rgb),
+            "lab" => Some(ColorSpace::Lab),
+            "oklab" => Some(ColorSpace::Oklab),
+            _ => None,
+        }
+    }
+
+    // Size of one sRGB step along an axis, roughly
+    pub fn step(self) -> f64 {
+        match self {
+            ColorSpace::Rgb => 1.0,
+            ColorSpace::Lab => 100.0 / 255.0,
+            ColorSpace::Oklab => 1.0 / 255.0,
+        }
+    }
+
+    pub fn to_coords(self, red: u8, grn: u8, blu: u8) -> [f64; 3] {
+        match self {
+            ColorSpace::Rgb => [red as f64, grn as f64, blu as f64],
+            ColorSpace::Lab => rgb_to_lab(red, grn, blu),
+            ColorSpace::Oklab => rgb_to_oklab(red, grn, blu),
+        }
+    }
+
+    // Back to sRGB. RGB coordinates are truncated rather than rounded to keep the pallets median cut
+    // has always made.
+    pub fn to_rgb(self, coords: [f64; 3]) -> [u8; 3] {
+        match self {
+            ColorSpace::Rgb => [coords[0] as u8, coords[1] as u8, coords[2] as u8],
+            ColorSpace::Lab => lab_to_rgb(coords),
+            ColorSpace::Oklab => oklab_to_rgb(coords),
+        }
+    }
+}
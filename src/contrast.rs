@@ -0,0 +1,183 @@
+// Contrast between text and background colors, and a pass that makes every pallet color readable
+// against the background by changing its lightness in OKLCH. Hue is kept, chroma only drops where
+// the lighter or darker color would be out of the sRGB gamut.
+use crate::color::{Color, Luminance};
+use crate::q_image::ColorChannel;
+
+// Whether the theme has a dark background with light text, or the other way around
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Mode {
+    #[default]
+    Dark,
+    Light,
+    // Light for images that are mostly light, from the median lightness of the histogram
+    Auto,
+}
+
+// Names accepted by Mode::from_name(), used for the --mode option
+pub const MODES: [&str; 3] = ["dark", "light", "auto"];
+
+// Auto mode picks a light theme when the median OKLab lightness of the image is above this
+pub const AUTO_LIGHT_LIGHTNESS: f64 = 0.6;
+
+// A light theme's background is lightened to at least this OKLab lightness
+pub const LIGHT_BACKGROUND: f64 = 0.9;
+
+// Contrast the colors of a light theme are darkened to when no other target is given
+pub const LIGHT_MODE_CONTRAST: Contrast = Contrast::Wcag(4.5);
+
+impl Mode {
+    pub fn from_name(name: &str) -> Option<Mode> {
+        match name {
+            "dark" => Some(Mode::Dark),
+            "light" => Some(Mode::Light),
+            "auto" => Some(Mode::Auto),
+            _ => None,
+        }
+    }
+
+    // Dark or light, deciding auto mode from the image's median lightness
+    pub fn resolve(self, median_lightness: f64) -> Mode {
+        match self {
+            Mode::Auto if median_lightness > AUTO_LIGHT_LIGHTNESS => Mode::Light,
+            Mode::Auto => Mode::Dark,
+            mode => mode,
+        }
+    }
+}
+
+// Raises the lightness of a light theme's background to LIGHT_BACKGROUND, keeping its hue.
+// Returns whether it had to be changed.
+pub fn lighten_background(background: &mut ColorChannel) -> bool {
+    let [l, chroma, hue] = background.color().to_oklch();
+    if l >= LIGHT_BACKGROUND {
+        return false;
+    }
+    let lighter = Color::from_oklch_in_gamut([LIGHT_BACKGROUND, chroma, hue]);
+    *background = ColorChannel::from_color(lighter, background.count);
+    true
+}
+
+// Minimum contrast every pallet color should have against the background
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Contrast {
+    // WCAG 2 contrast ratio, 1 to 21. 4.5 is AA for body text, 3 for large text.
+    Wcag(f64),
+    // APCA lightness contrast Lc, 0 to about 106 either way. 60 is the usual minimum for body text.
+    Apca(f64),
+}
+
+pub fn wcag_ratio(a: Color, b: Color) -> f64 {
+    let la = Luminance::Wcag.of(a);
+    let lb = Luminance::Wcag.of(b);
+    (la.max(lb) + 0.05) / (la.min(lb) + 0.05)
+}
+
+// APCA-W3 0.0.98G-4g, https://github.com/Myndex/apca-w3
+// Positive for dark text on a light background and negative the other way around.
+pub fn apca_lc(text: Color, background: Color) -> f64 {
+    let screen_y = |c: Color| {
+        let [r, g, b] = [c.red, c.grn, c.blu].map(|v| v.clamp(0.0, 1.0).powf(2.4));
+        let y = 0.212_672_9 * r + 0.715_152_2 * g + 0.072_175_0 * b;
+        // Soft clamp near black, where screens and eyes lose contrast
+        if y < 0.022 {
+            y + (0.022 - y).powf(1.414)
+        } else {
+            y
+        }
+    };
+    let y_txt = screen_y(text);
+    let y_bg = screen_y(background);
+    if (y_bg - y_txt).abs() < 0.0005 {
+        return 0.0;
+    }
+
+    let lc = if y_bg > y_txt {
+        let s = (y_bg.powf(0.56) - y_txt.powf(0.57)) * 1.14;
+        if s < 0.1 {
+            0.0
+        } else {
+            s - 0.027
+        }
+    } else {
+        let s = (y_bg.powf(0.65) - y_txt.powf(0.62)) * 1.14;
+        if s > -0.1 {
+            0.0
+        } else {
+            s + 0.027
+        }
+    };
+    lc * 100.0
+}
+
+impl Contrast {
+    // How far the color is from meeting the target, 0 or less when it does
+    fn shortfall(self, color: Color, background: Color) -> f64 {
+        match self {
+            Contrast::Wcag(ratio) => ratio - wcag_ratio(color, background),
+            Contrast::Apca(lc) => lc - apca_lc(color, background).abs(),
+        }
+    }
+
+    pub fn describe(self) -> String {
+        match self {
+            Contrast::Wcag(ratio) => format!("WCAG {}:1", ratio),
+            Contrast::Apca(lc) => format!("APCA Lc {}", lc),
+        }
+    }
+}
+
+// The smallest lightness change towards end (0 or 1) that meets the target, or the end itself.
+// Colors are rounded to what will be written before they are measured.
+fn search(lch: [f64; 3], end: f64, background: Color, target: Contrast) -> Color {
+    let [l, chroma, hue] = lch;
+    let written = |l: f64| {
+        let [r, g, b] = Color::from_oklch_in_gamut([l, chroma, hue]).to_rgb8();
+        Color::from_rgb8(r, g, b)
+    };
+    let (mut near, mut far) = (l, end);
+    for _ in 0..24 {
+        let mid = (near + far) / 2.0;
+        if target.shortfall(written(mid), background) <= 0.0 {
+            far = mid;
+        } else {
+            near = mid;
+        }
+    }
+    written(far)
+}
+
+// Changes the lightness of every pallet color that doesn't meet the target against the background,
+// moving it away from the background's lightness first. Colors that can't reach the target either
+// way end up as far as they can get. Returns the positions of the colors that were changed.
+pub fn enforce(pallet: &mut [ColorChannel], background: usize, target: Contrast) -> Vec<usize> {
+    let bg = pallet[background].color();
+    let bg_l = bg.to_oklch()[0];
+    let mut changed = Vec::new();
+
+    for (i, c) in pallet.iter_mut().enumerate() {
+        if i == background || target.shortfall(c.color(), bg) <= 0.0 {
+            continue;
+        }
+        let lch = c.color().to_oklch();
+        let (away, toward) = if lch[0] >= bg_l {
+            (1.0, 0.0)
+        } else {
+            (0.0, 1.0)
+        };
+        let mut best = search(lch, away, bg, target);
+        if target.shortfall(best, bg) > 0.0 {
+            let other = search(lch, toward, bg, target);
+            if target.shortfall(other, bg) < target.shortfall(best, bg) {
+                best = other;
+            }
+        }
+
+        let adjusted = ColorChannel::from_color(best, c.count);
+        if adjusted.rgb != c.rgb {
+            *c = adjusted;
+            changed.push(i);
+        }
+    }
+    changed
+}
@@ -0,0 +1,210 @@
+// Color vision deficiency. Simulates how a pallet looks to someone with protanopia, deuteranopia or
+// tritanopia, finds the colors that can't be told apart that way, and can move them apart by
+// changing their lightness, which all three keep.
+use crate::color::Color;
+use crate::delta_e::DeltaE;
+use crate::q_image::ColorChannel;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Deficiency {
+    // No working L (red) cones
+    Protan,
+    // No working M (green) cones, the most common
+    Deutan,
+    // No working S (blue) cones
+    Tritan,
+}
+
+pub const DEFICIENCIES: [Deficiency; 3] =
+    [Deficiency::Protan, Deficiency::Deutan, Deficiency::Tritan];
+
+// Names accepted by Deficiency::from_name(), all picks every deficiency
+pub const DEFICIENCY_NAMES: [&str; 4] = ["protan", "deutan", "tritan", "all"];
+
+// Colors closer than this delta E are reported as colliding unless --cvd-threshold says otherwise
+pub const DEFAULT_CVD_THRESHOLD: f64 = 10.0;
+
+// Most OKLab lightness the adaptation pass moves a color by, and the size of its steps
+const MAX_SHIFT: f64 = 0.2;
+const SHIFT_STEP: f64 = 0.01;
+// Times the pass looks for collisions again, moving one color can make it collide with another
+const ADAPT_ROUNDS: usize = 4;
+
+impl Deficiency {
+    // The deficiencies picked by a name from DEFICIENCY_NAMES
+    pub fn from_name(name: &str) -> Option<Vec<Deficiency>> {
+        match name {
+            "protan" => Some(vec![Deficiency::Protan]),
+            "deutan" => Some(vec![Deficiency::Deutan]),
+            "tritan" => Some(vec![Deficiency::Tritan]),
+            "all" => Some(DEFICIENCIES.to_vec()),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Deficiency::Protan => "protan",
+            Deficiency::Deutan => "deutan",
+            Deficiency::Tritan => "tritan",
+        }
+    }
+
+    // Machado, Oliveira and Fernandes, "A Physiologically-based Model for Simulation of Color
+    // Vision Deficiency" (2009), at severity 1. Applied to linear RGB.
+    fn matrix(self) -> [[f64; 3]; 3] {
+        match self {
+            Deficiency::Protan => [
+                [0.152_286, 1.052_583, -0.204_868],
+                [0.114_503, 0.786_281, 0.099_216],
+                [-0.003_882, -0.048_116, 1.051_998],
+            ],
+            Deficiency::Deutan => [
+                [0.367_322, 0.860_646, -0.227_968],
+                [0.280_085, 0.672_501, 0.047_413],
+                [-0.011_820, 0.042_940, 0.968_881],
+            ],
+            Deficiency::Tritan => [
+                [1.255_528, -0.076_749, -0.178_779],
+                [-0.078_411, 0.930_809, 0.147_602],
+                [0.004_733, 0.691_367, 0.303_900],
+            ],
+        }
+    }
+
+    // How the color looks with this deficiency
+    pub fn simulate(self, color: Color) -> Color {
+        let rgb = color.to_linear();
+        let m = self.matrix();
+        let row = |r: [f64; 3]| (r[0] * rgb[0] + r[1] * rgb[1] + r[2] * rgb[2]).clamp(0.0, 1.0);
+        Color::from_linear([row(m[0]), row(m[1]), row(m[2])])
+    }
+}
+
+fn simulated_distance(
+    a: &ColorChannel,
+    b: &ColorChannel,
+    deficiency: Deficiency,
+    metric: DeltaE,
+) -> f64 {
+    let a = ColorChannel::from_color(deficiency.simulate(a.color()), 0);
+    let b = ColorChannel::from_color(deficiency.simulate(b.color()), 0);
+    metric.distance(&metric.coords(&a), &metric.coords(&b))
+}
+
+// Pairs of colors that can be told apart with normal vision but are closer than the threshold with
+// the deficiency, as (i, j, simulated distance), closest first
+pub fn collisions(
+    pallet: &[ColorChannel],
+    deficiency: Deficiency,
+    metric: DeltaE,
+    threshold: f64,
+) -> Vec<(usize, usize, f64)> {
+    let mut pairs = Vec::new();
+    for i in 0..pallet.len() {
+        for j in i + 1..pallet.len() {
+            let normal = metric.distance(&metric.coords(&pallet[i]), &metric.coords(&pallet[j]));
+            let simulated = simulated_distance(&pallet[i], &pallet[j], deficiency, metric);
+            if normal >= threshold && simulated < threshold {
+                pairs.push((i, j, simulated));
+            }
+        }
+    }
+    pairs.sort_by(|a, b| a.2.partial_cmp(&b.2).unwrap());
+    pairs
+}
+
+// Closest any other pallet color gets to a replacement for the color at i with any of the
+// deficiencies. Colors that were already too close to tell apart with normal vision are skipped.
+fn closest(
+    pallet: &[ColorChannel],
+    i: usize,
+    color: &ColorChannel,
+    deficiencies: &[Deficiency],
+    metric: DeltaE,
+    threshold: f64,
+) -> f64 {
+    let original = metric.coords(&pallet[i]);
+    let mut min = f64::MAX;
+    for (j, other) in pallet.iter().enumerate() {
+        if j == i || metric.distance(&original, &metric.coords(other)) < threshold {
+            continue;
+        }
+        for &deficiency in deficiencies {
+            min = min.min(simulated_distance(color, other, deficiency, metric));
+        }
+    }
+    min
+}
+
+// Moves colliding colors apart for the deficiencies. Of each colliding pair the less common color is
+// moved, by the smallest lightness change that takes it past the threshold from every other color,
+// or as far as MAX_SHIFT gets it when none does. Hue and chroma are kept where they fit in sRGB so
+// the color stays close to the image. Returns the positions of the colors that were changed.
+pub fn adapt(
+    pallet: &mut [ColorChannel],
+    deficiencies: &[Deficiency],
+    metric: DeltaE,
+    threshold: f64,
+) -> Vec<usize> {
+    let mut changed = Vec::new();
+    for _ in 0..ADAPT_ROUNDS {
+        let mut moved = false;
+        for &deficiency in deficiencies {
+            for (i, j, _) in collisions(pallet, deficiency, metric, threshold) {
+                // An earlier move this round may have fixed the pair already
+                if simulated_distance(&pallet[i], &pallet[j], deficiency, metric) >= threshold {
+                    continue;
+                }
+                let (mover, other) = if pallet[j].count <= pallet[i].count {
+                    (j, i)
+                } else {
+                    (i, j)
+                };
+                let [l, chroma, hue] = pallet[mover].color().to_oklch();
+                let other_l = pallet[other].color().to_oklch()[0];
+                let away = if l > other_l || (l == other_l && l < 0.5) {
+                    1.0
+                } else {
+                    -1.0
+                };
+
+                let mut best = pallet[mover];
+                let mut best_distance =
+                    closest(pallet, mover, &best, deficiencies, metric, threshold);
+                let steps = (MAX_SHIFT / SHIFT_STEP).round() as usize;
+                'search: for step in 1..=steps {
+                    for &direction in &[away, -away] {
+                        let shifted = l + direction * step as f64 * SHIFT_STEP;
+                        if !(0.0..=1.0).contains(&shifted) {
+                            continue;
+                        }
+                        let color = Color::from_oklch_in_gamut([shifted, chroma, hue]);
+                        let candidate = ColorChannel::from_color(color, pallet[mover].count);
+                        let distance =
+                            closest(pallet, mover, &candidate, deficiencies, metric, threshold);
+                        if distance > best_distance {
+                            best = candidate;
+                            best_distance = distance;
+                            if distance >= threshold {
+                                break 'search;
+                            }
+                        }
+                    }
+                }
+                if best.rgb != pallet[mover].rgb {
+                    pallet[mover] = best;
+                    moved = true;
+                    if !changed.contains(&mover) {
+                        changed.push(mover);
+                    }
+                }
+            }
+        }
+        if !moved {
+            break;
+        }
+    }
+    changed.sort_unstable();
+    changed
+}
@@ -0,0 +1,134 @@
+// Color difference metrics, how far apart two colors look. CIEDE2000 is the reference, CIE76 is the
+// straight line distance in CIELAB and the OKLab distance is scaled by 100 so all three give values
+// around 1 for the smallest difference that can be seen.
+use crate::q_image::ColorChannel;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DeltaE {
+    // The default, DEFAULT_DEDUPE_THRESHOLD was picked for it
+    #[default]
+    Cie76,
+    Ciede2000,
+    Oklab,
+}
+
+// Names accepted by DeltaE::from_name(), used for the --delta-e and distance --metric options
+pub const DELTA_E_METRICS: [&str; 3] = ["cie76", "ciede2000", "oklab"];
+
+impl DeltaE {
+    pub fn from_name(name: &str) -> Option<DeltaE> {
+        match name {
+            "cie76" => Some(DeltaE::Cie76),
+            "ciede2000" => Some(DeltaE::Ciede2000),
+            "oklab" => Some(DeltaE::Oklab),
+            _ => None,
+        }
+    }
+
+    // Coordinates the metric measures in, CIELAB or OKLab. Worth keeping around when the same color
+    // is compared many times.
+    pub fn coords(self, c: &ColorChannel) -> [f64; 3] {
+        match self {
+            DeltaE::Cie76 | DeltaE::Ciede2000 => c.color().to_lab(),
+            DeltaE::Oklab => c.color().to_oklab(),
+        }
+    }
+
+    // Difference between two sets of coordinates from coords()
+    pub fn distance(self, a: &[f64; 3], b: &[f64; 3]) -> f64 {
+        match self {
+            DeltaE::Cie76 => cie76(a, b),
+            DeltaE::Ciede2000 => ciede2000(a, b),
+            DeltaE::Oklab => 100.0 * euclidean(a, b),
+        }
+    }
+}
+
+fn euclidean(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt()
+}
+
+pub fn cie76(lab1: &[f64; 3], lab2: &[f64; 3]) -> f64 {
+    euclidean(lab1, lab2)
+}
+
+// Hue angle in degrees from 0 up to 360, 0 for greys
+fn hue(a: f64, b: f64) -> f64 {
+    if a == 0.0 && b == 0.0 {
+        0.0
+    } else {
+        b.atan2(a).to_degrees().rem_euclid(360.0)
+    }
+}
+
+// CIEDE2000 as written up by Sharma, Wu and Dalal, "The CIEDE2000 Color-Difference Formula:
+// Implementation Notes, Supplementary Test Data, and Mathematical Observations" (2005).
+// The weighting factors kL, kC and kH are all 1.
+pub fn ciede2000(lab1: &[f64; 3], lab2: &[f64; 3]) -> f64 {
+    let [l1, a1, b1] = *lab1;
+    let [l2, a2, b2] = *lab2;
+    let pow7 = |c: f64| c.powi(7);
+    let twenty_five_7 = pow7(25.0);
+
+    // Stretch a* so greys and colors near the neutral axis are compared fairly
+    let c_bar = (a1.hypot(b1) + a2.hypot(b2)) / 2.0;
+    let g = 0.5 * (1.0 - (pow7(c_bar) / (pow7(c_bar) + twenty_five_7)).sqrt());
+    let a1 = (1.0 + g) * a1;
+    let a2 = (1.0 + g) * a2;
+    let c1 = a1.hypot(b1);
+    let c2 = a2.hypot(b2);
+    let h1 = hue(a1, b1);
+    let h2 = hue(a2, b2);
+
+    let delta_l = l2 - l1;
+    let delta_c = c2 - c1;
+    let delta_h = if c1 * c2 == 0.0 {
+        0.0
+    } else if h2 - h1 > 180.0 {
+        h2 - h1 - 360.0
+    } else if h2 - h1 < -180.0 {
+        h2 - h1 + 360.0
+    } else {
+        h2 - h1
+    };
+    let delta_h = 2.0 * (c1 * c2).sqrt() * (delta_h / 2.0).to_radians().sin();
+
+    let l_bar = (l1 + l2) / 2.0;
+    let c_bar = (c1 + c2) / 2.0;
+    let h_bar = if c1 * c2 == 0.0 {
+        h1 + h2
+    } else if (h1 - h2).abs() <= 180.0 {
+        (h1 + h2) / 2.0
+    } else if h1 + h2 < 360.0 {
+        (h1 + h2 + 360.0) / 2.0
+    } else {
+        (h1 + h2 - 360.0) / 2.0
+    };
+
+    let cos = |degrees: f64| degrees.to_radians().cos();
+    let t =
+        1.0 - 0.17 * cos(h_bar - 30.0) + 0.24 * cos(2.0 * h_bar) + 0.32 * cos(3.0 * h_bar + 6.0)
+            - 0.20 * cos(4.0 * h_bar - 63.0);
+    let delta_theta = 30.0 * (-((h_bar - 275.0) / 25.0).powi(2)).exp();
+    let r_c = 2.0 * (pow7(c_bar) / (pow7(c_bar) + twenty_five_7)).sqrt();
+    let s_l = 1.0 + 0.015 * (l_bar - 50.0).powi(2) / (20.0 + (l_bar - 50.0).powi(2)).sqrt();
+    let s_c = 1.0 + 0.045 * c_bar;
+    let s_h = 1.0 + 0.015 * c_bar * t;
+    let r_t = -(2.0 * delta_theta).to_radians().sin() * r_c;
+
+    let (l, c, h) = (delta_l / s_l, delta_c / s_c, delta_h / s_h);
+    (l * l + c * c + h * h + r_t * c * h).sqrt()
+}
+
+// Difference between every pair of colors, closest pair first
+pub fn pairwise(colors: &[ColorChannel], metric: DeltaE) -> Vec<(usize, usize, f64)> {
+    let coords: Vec<[f64; 3]> = colors.iter().map(|c| metric.coords(c)).collect();
+    let mut pairs = Vec::new();
+    for i in 0..coords.len() {
+        for j in i + 1..coords.len() {
+            pairs.push((i, j, metric.distance(&coords[i], &coords[j])));
+        }
+    }
+    pairs.sort_by(|a, b| a.2.partial_cmp(&b.2).unwrap());
+    pairs
+}
@@ -1,25 +1,41 @@
-use clap::{App, Arg};
+use adjust::Adjustment;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use color::Luminance;
+use contrast::{Contrast, Mode};
+use cvd::Deficiency;
+use delta_e::DeltaE;
 use dirs::home_dir;
-use float_cmp::*;
-use image::ImageFormat;
+use palette::{complete_pallet, distinct_pallet, Completion, Scheme, DEFAULT_DEDUPE_THRESHOLD};
+use preprocess::{Downsample, Target};
+use q_image::{AlphaMode, ColorChannel, Histogram, HistogramMode, HistogramOptions, Quantizer};
 use rand::seq::SliceRandom;
 use rand::thread_rng;
-use std::collections::HashMap;
+use slots::Slots;
 use std::fs::*;
-use std::io::{BufRead, BufReader, Error, Write};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use theme::{parse_color, Theme};
+mod adjust;
+mod color;
+mod contrast;
+mod cvd;
+mod delta_e;
+mod palette;
+mod preprocess;
 mod q_image;
+mod slots;
+mod template;
 mod test;
+mod theme;
 
 fn main() -> Result<(), Error> {
     let cli = App::new("rusty-theme")
         .version("1.0")
         .help("myapp v1.0\n\
-           Generate colorschemes from .jpg/.jpeg\n\
+           Generate colorschemes from images (.jpg, .png, .webp, ...)\n\
            (C) aag3@pdx.edu\n\n\
-
            USAGE: rusty-theme -i <image_file> -s <output_name> [Options]\n\n\
-
            Options:\n\
            -h, --help           Display this message\n\
            -i, --image <file>   Use supplied file for colorscheme\n\
@@ -27,7 +43,48 @@ fn main() -> Result<(), Error> {
            -r                   Reload the default .Xresources file cannot use with -n\n\
            -n --now             Reload Xresources with generated colorscheme\n\
            --random             Shuffle the colors in the generated colorscheme before writing to file\n\
-           -c --colorscheme     Load the provided colorscheme file made with the tool in xrdb")
+           -c --colorscheme     Load the provided colorscheme file made with the tool in xrdb\n\
+           --colors <n>         Number of colors in the colorscheme, 1 to 256 (default 16)\n\
+           --complete <strategy>  Make up colors the image doesn't have (none, interpolate, lightness, hue)\n\
+           --dedupe <delta-e>   Merge colors closer than this delta E (default 2.3, 0 keeps them all)\n\
+           --delta-e <metric>   Color difference for --dedupe and --report-drift (cie76, ciede2000, oklab, default cie76)\n\
+           --luminance <model>  How *background and *foreground are picked (wcag, rec709, oklab, lab)\n\
+           --slots <strategy>   Order of the colors (population, ansi for red in color1, green in color2, ...)\n\
+           --scheme <scheme>    raw takes every color from the image, derived makes the second half brighter versions of the first\n\
+           --bright-lightness <n>  OKLab lightness added to the derived bright colors (default 0.1)\n\
+           --bright-chroma <factor>  Chroma of the derived bright colors times this (default 1.15)\n\
+           --mode <mode>        dark, light or auto to pick from the image (default dark)\n\
+           --min-contrast <ratio>  Lighten or darken colors to this WCAG contrast ratio against the background\n\
+           --min-apca <lc>      Lighten or darken colors to this APCA Lc against the background\n\
+           --saturate <percent>  Make the colors more vivid, --desaturate <percent> for less\n\
+           --lighten <n>        Add n hundredths to the OKLab lightness of every color, --darken <n> to subtract\n\
+           --contrast <percent>  Spread the lightness of the colors apart, negative to bring them closer\n\
+           --hue-rotate <degrees>  Turn the hue of every color\n\
+           --temperature <n>    Make the colors warmer, -100 to 100, negative for cooler\n\
+           --adjust <file>      Adjustments from a file, one \"<adjustment> <amount>\" per line, run first. The options above run in the order given\n\
+           --cvd-report         List the colors that collide for protan, deutan and tritan color blindness\n\
+           --cvd-adapt <deficiency>  Move colliding colors apart for protan, deutan, tritan or all\n\
+           --cvd-threshold <delta-e>  Colors closer than this collide (default 10)\n\
+           --templates <dir>    Render every template in dir with the colors of the colorscheme\n\
+           --template-output <dir>  Where rendered templates go (default ~/.cache/rusty-theme/<name>)\n\
+           --algorithm <name>   Quantization algorithm used to find the pallet (auto, median-cut, octree, wu)\n\
+           --space <name>       Color space median cut works in (rgb, lab, oklab)\n\
+           --split <strategy>   Bucket median cut splits next (level, volume, variance)\n\
+           --kmeans             Refine the pallet with k-means, slower but more accurate colors\n\
+           --kmeans-iterations <n>  Maximum number of k-means passes (default 10)\n\
+           --alpha-threshold <alpha>  Skip pixels with less alpha than this (default 128)\n\
+           --alpha-weighted     Weight pixels by their alpha instead of skipping them\n\
+           --histogram <mode>   How the colors of the image are counted (exact, rgb555, sorted)\n\
+           --threads <n>        Threads used to count the colors (default one per core)\n\
+           --max-pixels <n>     Shrink the image to at most n pixels before finding the pallet\n\
+           --scale <factor>     Scale the image by factor (0-1] before finding the pallet\n\
+           --filter <name>      Resize filter (nearest, triangle, catmullrom, gaussian, lanczos3)\n\
+           --report-drift       Report how far the pallet is from the full size image's pallet\n\n\
+           Subcommands:\n\
+           distance [--metric <metric>] <color or file>...  Print the delta E between every pair of colors,\n\
+           given as hex colors or colorscheme files\n\
+           cvd [--metric <metric>] [--threshold <delta-e>] <color or file>...  List the colors that collide\n\
+           for protan, deutan and tritan color blindness")
         .usage("rusty-theme [-i <image file path>]\n\t-- [-c Immediately load generated colorscheme]\n\t-- [-r Load the user's default .Xresources file in their home directory] (Cannot be used with the -c Option)]\n\t-- [-s <Desired colorscheme name>]")
         .about("Use existing images to calculate a pallet for Xresources")
         .arg(
@@ -69,19 +126,406 @@ fn main() -> Result<(), Error> {
                 .long("reload")
                 .help("Reload Xresource files to update system colorscheme"),
         )
+        .arg(
+            Arg::with_name("colors")
+                .long("colors")
+                .value_name("n")
+                .help("Number of colors in the generated colorscheme (default 16)")
+                .validator(|v| match v.parse::<u32>() {
+                    Ok(n) if n > 0 && n <= 256 => Ok(()),
+                    _ => Err(String::from("expected a number of colors from 1 to 256")),
+                })
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("complete")
+                .long("complete")
+                .value_name("strategy")
+                .help("How colors are made up when the image doesn't have enough distinct ones")
+                .possible_values(&palette::COMPLETIONS)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dedupe")
+                .long("dedupe")
+                .value_name("delta-e")
+                .help("Merge colors closer than this delta E (default 2.3, 0 keeps them all)")
+                .validator(|v| match v.parse::<f64>() {
+                    Ok(d) if d >= 0.0 => Ok(()),
+                    _ => Err(String::from("expected a delta E of 0 or more")),
+                })
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("delta-e")
+                .long("delta-e")
+                .value_name("metric")
+                .help("Color difference used to merge duplicates and report drift (default cie76)")
+                .possible_values(&delta_e::DELTA_E_METRICS)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("luminance")
+                .long("luminance")
+                .value_name("model")
+                .help("Luminance model used to pick the background and foreground (default wcag)")
+                .possible_values(&color::LUMINANCE_MODELS)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("slots")
+                .long("slots")
+                .value_name("strategy")
+                .help("How colors are assigned to *colorN, by population or by ANSI role")
+                .possible_values(&slots::SLOT_STRATEGIES)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("scheme")
+                .long("scheme")
+                .value_name("scheme")
+                .help("Take every color from the image, or derive the bright half from the others")
+                .possible_values(&palette::SCHEMES)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bright-lightness")
+                .long("bright-lightness")
+                .value_name("n")
+                .help("OKLab lightness added to derived bright colors (default 0.1)")
+                .requires("scheme")
+                .validator(|v| match v.parse::<f64>() {
+                    Ok(l) if (0.0..=1.0).contains(&l) => Ok(()),
+                    _ => Err(String::from("expected a lightness from 0 to 1")),
+                })
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bright-chroma")
+                .long("bright-chroma")
+                .value_name("factor")
+                .help("Chroma of derived bright colors is multiplied by this (default 1.15)")
+                .requires("scheme")
+                .validator(|v| match v.parse::<f64>() {
+                    Ok(c) if c >= 0.0 => Ok(()),
+                    _ => Err(String::from("expected a chroma factor of 0 or more")),
+                })
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("mode")
+                .long("mode")
+                .value_name("mode")
+                .help("Dark or light theme, auto picks from the image's lightness (default dark)")
+                .possible_values(&contrast::MODES)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("min-contrast")
+                .long("min-contrast")
+                .value_name("ratio")
+                .help("Make every color reach this WCAG contrast ratio against the background")
+                .validator(|v| match v.parse::<f64>() {
+                    Ok(r) if (1.0..=21.0).contains(&r) => Ok(()),
+                    _ => Err(String::from("expected a contrast ratio from 1 to 21")),
+                })
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("min-apca")
+                .long("min-apca")
+                .value_name("lc")
+                .help("Make every color reach this APCA Lc against the background")
+                .conflicts_with("min-contrast")
+                .validator(|v| match v.parse::<f64>() {
+                    Ok(lc) if (0.0..=106.0).contains(&lc) => Ok(()),
+                    _ => Err(String::from("expected an APCA Lc from 0 to 106")),
+                })
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("saturate")
+                .long("saturate")
+                .value_name("percent")
+                .help("Increase the chroma of every color by this percent")
+                .validator(is_number)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("desaturate")
+                .long("desaturate")
+                .value_name("percent")
+                .help("Decrease the chroma of every color by this percent")
+                .validator(is_number)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("lighten")
+                .long("lighten")
+                .value_name("n")
+                .help("Add n hundredths to the OKLab lightness of every color")
+                .validator(is_number)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("darken")
+                .long("darken")
+                .value_name("n")
+                .help("Subtract n hundredths from the OKLab lightness of every color")
+                .validator(is_number)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("contrast")
+                .long("contrast")
+                .value_name("percent")
+                .help("Spread the lightness of the colors apart from their mean by this percent")
+                .validator(is_number)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("hue-rotate")
+                .long("hue-rotate")
+                .value_name("degrees")
+                .help("Turn the hue of every color by this many degrees")
+                .validator(is_number)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("temperature")
+                .long("temperature")
+                .value_name("n")
+                .help("Shift the colors towards orange, or blue when negative")
+                .validator(is_number)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("adjust")
+                .long("adjust")
+                .value_name("file")
+                .help("Run the adjustments in this file before the ones given as options")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cvd-report")
+                .long("cvd-report")
+                .help("List the colors that can't be told apart with each color vision deficiency"),
+        )
+        .arg(
+            Arg::with_name("cvd-adapt")
+                .long("cvd-adapt")
+                .value_name("deficiency")
+                .help("Move colors that collide with a color vision deficiency apart")
+                .possible_values(&cvd::DEFICIENCY_NAMES)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cvd-threshold")
+                .long("cvd-threshold")
+                .value_name("delta-e")
+                .help("Colors closer than this collide (default 10)")
+                .validator(is_threshold)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("templates")
+                .long("templates")
+                .value_name("dir")
+                .help("Render the templates in this directory with the colors of the colorscheme")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("template-output")
+                .long("template-output")
+                .value_name("dir")
+                .help("Directory rendered templates are written to")
+                .requires("templates")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("algorithm")
+                .long("algorithm")
+                .value_name("name")
+                .help("Quantization algorithm used to build the pallet")
+                .possible_values(&q_image::ALGORITHMS)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("space")
+                .long("space")
+                .value_name("name")
+                .help("Color space median cut splits and averages colors in")
+                .possible_values(&color::COLOR_SPACES)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("split")
+                .long("split")
+                .value_name("strategy")
+                .help("How median cut picks the next bucket to split")
+                .possible_values(&q_image::SPLIT_STRATEGIES)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("kmeans")
+                .long("kmeans")
+                .help("Refine the quantized pallet with k-means"),
+        )
+        .arg(
+            Arg::with_name("kmeans-iterations")
+                .long("kmeans-iterations")
+                .value_name("n")
+                .help("Maximum number of k-means passes used by --kmeans")
+                .requires("kmeans")
+                .validator(|v| match v.parse::<usize>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err(String::from("expected a number of iterations")),
+                })
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("alpha-threshold")
+                .long("alpha-threshold")
+                .value_name("alpha")
+                .help("Skip pixels with less alpha than this (0-255) when finding the pallet")
+                .validator(|v| match v.parse::<u8>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err(String::from("expected an alpha value from 0 to 255")),
+                })
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("alpha-weighted")
+                .long("alpha-weighted")
+                .help("Weight pixels by their alpha instead of skipping transparent ones")
+                .conflicts_with("alpha-threshold"),
+        )
+        .arg(
+            Arg::with_name("histogram")
+                .long("histogram")
+                .value_name("mode")
+                .help("How the colors of the image are counted")
+                .possible_values(&q_image::HISTOGRAM_MODES)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .value_name("n")
+                .help("Number of threads used to count the colors of the image, one per core by default")
+                .validator(|v| match v.parse::<usize>() {
+                    Ok(n) if n > 0 => Ok(()),
+                    _ => Err(String::from("expected a number of threads above 0")),
+                })
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-pixels")
+                .long("max-pixels")
+                .value_name("n")
+                .help("Shrink the image to at most this many pixels before finding the pallet, e.g. 262144 for 512x512")
+                .validator(|v| match v.parse::<u32>() {
+                    Ok(n) if n > 0 => Ok(()),
+                    _ => Err(String::from("expected a number of pixels above 0")),
+                })
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("scale")
+                .long("scale")
+                .value_name("factor")
+                .help("Scale the image by this factor (0-1] before finding the pallet")
+                .conflicts_with("max-pixels")
+                .validator(|v| match v.parse::<f64>() {
+                    Ok(f) if f > 0.0 && f <= 1.0 => Ok(()),
+                    _ => Err(String::from("expected a scale above 0 and at most 1")),
+                })
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("filter")
+                .long("filter")
+                .value_name("name")
+                .help("Filter used when shrinking the image")
+                .possible_values(&preprocess::FILTERS)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("report-drift")
+                .long("report-drift")
+                .help("Also find the pallet of the full size image and report how far apart they are"),
+        )
         .arg(
             Arg::with_name("list")
                 .short("l")
                 .long("list")
                 .help("Print currently loaded theme in Xresources Database."),
-                );
+                )
+        .subcommand(
+            SubCommand::with_name("distance")
+                .about("Print the delta E between every pair of colors")
+                .arg(
+                    Arg::with_name("metric")
+                        .long("metric")
+                        .value_name("metric")
+                        .help("Color difference to use (default cie76, as for --delta-e)")
+                        .possible_values(&delta_e::DELTA_E_METRICS)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("colors")
+                        .index(1)
+                        .help("Hex colors like #1d2021 or colorscheme files")
+                        .multiple(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("cvd")
+                .about("List the colors that collide with each color vision deficiency")
+                .arg(
+                    Arg::with_name("metric")
+                        .long("metric")
+                        .value_name("metric")
+                        .help("Color difference to use (default cie76, as for --delta-e)")
+                        .possible_values(&delta_e::DELTA_E_METRICS)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("threshold")
+                        .long("threshold")
+                        .value_name("delta-e")
+                        .help("Colors closer than this collide (default 10)")
+                        .validator(is_threshold)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("colors")
+                        .index(1)
+                        .help("Hex colors like #1d2021 or colorscheme files")
+                        .multiple(true)
+                        .required(true),
+                ),
+        );
 
     let matches = cli.get_matches();
     let save_file: &str;
     let random = matches.is_present("random");
+    let algorithm = matches.value_of("algorithm").unwrap_or("auto");
+    let space = matches.value_of("space").unwrap_or("rgb");
+    let split = matches.value_of("split").unwrap_or("volume");
+    let histogram_mode = matches.value_of("histogram").unwrap_or("exact");
+
+    if let Some(distance) = matches.subcommand_matches("distance") {
+        return print_distances(distance);
+    }
+    if let Some(cvd) = matches.subcommand_matches("cvd") {
+        return check_cvd(cvd);
+    }
 
     if matches.is_present("colorscheme") {
         let file = matches.value_of("colorscheme").unwrap();
+        render_templates(&matches, file)?;
         let p_output = Command::new("xrdb")
             .arg(file)
             .status()
@@ -101,14 +545,97 @@ fn main() -> Result<(), Error> {
     // Load Pallet and apply colorscheme from a JPEG file
     if matches.is_present("image") {
         let image_file_name = matches.value_of("image").unwrap();
+        let q_options = q_image::QuantizerOptions {
+            space: color::ColorSpace::from_name(space).unwrap(),
+            split: q_image::SplitStrategy::from_name(split).unwrap(),
+        };
+        let mut quantizer = q_image::quantizer_from_name(algorithm, &q_options).unwrap();
+        let histogram = HistogramOptions {
+            alpha: if matches.is_present("alpha-weighted") {
+                AlphaMode::Weighted
+            } else {
+                AlphaMode::Threshold(
+                    matches
+                        .value_of("alpha-threshold")
+                        .map_or(q_image::DEFAULT_ALPHA_THRESHOLD, |a| a.parse().unwrap()),
+                )
+            },
+            mode: HistogramMode::from_name(histogram_mode).unwrap(),
+            threads: matches
+                .value_of("threads")
+                .map_or(0, |n| n.parse().unwrap()),
+        };
+        let downsample = Downsample {
+            target: if let Some(scale) = matches.value_of("scale") {
+                Target::Scale(scale.parse().unwrap())
+            } else if let Some(max) = matches.value_of("max-pixels") {
+                Target::MaxPixels(max.parse().unwrap())
+            } else {
+                Target::Full
+            },
+            filter: preprocess::filter_from_name(matches.value_of("filter").unwrap_or("triangle"))
+                .unwrap(),
+            report_drift: matches.is_present("report-drift"),
+        };
+        let options = ThemeOptions {
+            random,
+            pallet_size: matches
+                .value_of("colors")
+                .map_or(DEFAULT_PALLET_SIZE, |n| n.parse().unwrap()),
+            histogram,
+            downsample,
+            completion: Completion::from_name(matches.value_of("complete").unwrap_or("lightness"))
+                .unwrap(),
+            dedupe: matches
+                .value_of("dedupe")
+                .map_or(DEFAULT_DEDUPE_THRESHOLD, |d| d.parse().unwrap()),
+            metric: DeltaE::from_name(matches.value_of("delta-e").unwrap_or("cie76")).unwrap(),
+            luminance: Luminance::from_name(matches.value_of("luminance").unwrap_or("wcag"))
+                .unwrap(),
+            contrast: if let Some(ratio) = matches.value_of("min-contrast") {
+                Some(Contrast::Wcag(ratio.parse().unwrap()))
+            } else {
+                matches
+                    .value_of("min-apca")
+                    .map(|lc| Contrast::Apca(lc.parse().unwrap()))
+            },
+            slots: Slots::from_name(matches.value_of("slots").unwrap_or("population")).unwrap(),
+            scheme: match matches.value_of("scheme").unwrap_or("raw") {
+                "derived" => Scheme::Derived {
+                    lightness: matches
+                        .value_of("bright-lightness")
+                        .map_or(palette::DEFAULT_BRIGHT_LIGHTNESS, |l| l.parse().unwrap()),
+                    chroma: matches
+                        .value_of("bright-chroma")
+                        .map_or(palette::DEFAULT_BRIGHT_CHROMA, |c| c.parse().unwrap()),
+                },
+                _ => Scheme::Raw,
+            },
+            mode: Mode::from_name(matches.value_of("mode").unwrap_or("dark")).unwrap(),
+            adjustments: read_adjustments(&matches)?,
+            cvd_report: matches.is_present("cvd-report"),
+            cvd_adapt: matches
+                .value_of("cvd-adapt")
+                .map_or_else(Vec::new, |d| Deficiency::from_name(d).unwrap()),
+            cvd_threshold: matches
+                .value_of("cvd-threshold")
+                .map_or(cvd::DEFAULT_CVD_THRESHOLD, |t| t.parse().unwrap()),
+        };
+        if matches.is_present("kmeans") {
+            let iterations = matches
+                .value_of("kmeans-iterations")
+                .map_or(q_image::REFINE_ITERATIONS, |n| n.parse().unwrap());
+            quantizer = Box::new(q_image::KMeansRefine::new(quantizer, iterations));
+        }
         if matches.is_present("save") {
             save_file = matches.value_of("save").unwrap();
             println!("{}", save_file);
-            colors_from_image(image_file_name, save_file, random)?;
+            colors_from_image(image_file_name, save_file, quantizer.as_mut(), &options)?;
         } else {
-            colors_from_image(image_file_name, "", random)?;
+            colors_from_image(image_file_name, "", quantizer.as_mut(), &options)?;
             save_file = "colorscheme";
         }
+        render_templates(&matches, save_file)?;
         // Reload colorscheme  file
         if matches.is_present("now") {
             let p_output = Command::new("xrdb")
@@ -167,159 +694,515 @@ fn main() -> Result<(), Error> {
     Ok(())
 }
 
-fn shuffle_colors(colormap: &HashMap<String, f64>) -> Vec<String> {
-    let mut names = Vec::new();
-    let mut hex = Vec::new();
-    let mut rng = thread_rng();
-
-    for key in colormap.keys() {
-        let name_and_hex: Vec<&str> = key.split(' ').collect();
-        names.push(name_and_hex[0]);
-        hex.push(name_and_hex[1]);
-        names.shuffle(&mut rng);
-        hex.shuffle(&mut rng);
+fn list_loaded_colors() {
+    let current_colors = xrdb::Colors::new("*").unwrap();
+
+    // Read back through Theme like any other colorscheme
+    let mut resources = vec![
+        ("background", current_colors.bg),
+        ("foreground", current_colors.fg),
+        ("cursorColor", current_colors.cursor),
+    ];
+    let names: Vec<String> = (0..current_colors.colors.len())
+        .map(|x| format!("color{}", x))
+        .collect();
+    resources.extend(names.iter().map(String::as_str).zip(current_colors.colors));
+    let text: String = resources
+        .into_iter()
+        .filter_map(|(name, value)| Some(format!("*{}: {}\n", name, value?)))
+        .collect();
+
+    println!("Current colorscheme loaded by Xresources database\n");
+    match Theme::from_xresources(&text) {
+        Ok(theme) => print!("{}", theme.to_xresources()),
+        Err(e) => println!("Loaded colorscheme is incomplete, {}", e),
+    }
+}
+
+fn is_number(v: String) -> Result<(), String> {
+    match v.parse::<f64>() {
+        Ok(n) if n.is_finite() => Ok(()),
+        _ => Err(String::from("expected a number")),
     }
+}
 
-    let mut new_strings = Vec::new();
-    for name_hex in names.iter().zip(hex.iter_mut()) {
-        let (n, h) = name_hex;
-        let full_string = format!("{}{}", n, h);
-        new_strings.push(full_string.clone());
+fn is_threshold(v: String) -> Result<(), String> {
+    match v.parse::<f64>() {
+        Ok(t) if t > 0.0 => Ok(()),
+        _ => Err(String::from("expected a delta E above 0")),
     }
+}
 
-    new_strings
+// Adjustments from the --adjust file followed by the ones given as options, in the order they were
+// given
+fn read_adjustments(matches: &ArgMatches) -> Result<Vec<Adjustment>, Error> {
+    let mut adjustments = match matches.value_of("adjust") {
+        Some(file) => adjust::parse_adjustments(&read_to_string(file)?)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", file, e)))?,
+        None => Vec::new(),
+    };
+    let args: Vec<String> = std::env::args().collect();
+    for name in adjustment_order(&args) {
+        if let Some(amount) = matches.value_of(name) {
+            adjustments.push(Adjustment::from_name(name, amount.parse().unwrap()).unwrap());
+        }
+    }
+    Ok(adjustments)
 }
 
-fn list_loaded_colors() {
-    let current_colors = xrdb::Colors::new("*");
+// The adjustment options in the order they appear in the arguments. clap doesn't keep the order of
+// different options, so it is found from the arguments themselves.
+fn adjustment_order(args: &[String]) -> Vec<&'static str> {
+    let mut given: Vec<(usize, &str)> = adjust::ADJUSTMENTS
+        .iter()
+        .filter_map(|&name| {
+            let flag = format!("--{}", name);
+            let with_value = format!("{}=", flag);
+            args.iter()
+                .position(|a| *a == flag || a.starts_with(&with_value))
+                .map(|i| (i, name))
+        })
+        .collect();
+    given.sort_unstable();
+    given.into_iter().map(|(_, name)| name).collect()
+}
 
-    let fg = current_colors.clone().unwrap().fg.unwrap();
-    let bg = current_colors.clone().unwrap().bg.unwrap();
-    println!("Current colorscheme loaded by Xresources database\n");
-    println!("fg = {:?}", fg);
-    println!("bg = {:?}", bg);
+// Renders the --templates directory with the colors of a colorscheme file, into --template-output or
+// a directory named after the colorscheme in ~/.cache/rusty-theme
+fn render_templates(matches: &ArgMatches, scheme: &str) -> Result<(), Error> {
+    let templates = match matches.value_of("templates") {
+        Some(dir) => Path::new(dir),
+        None => return Ok(()),
+    };
+    let output = match matches.value_of("template-output") {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            let mut dir = home_dir().ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    "cannot find home directory for templates, use --template-output",
+                )
+            })?;
+            dir.push(".cache/rusty-theme");
+            dir.push(Path::new(scheme).file_name().unwrap_or_default());
+            dir
+        }
+    };
 
-    for (x, color) in current_colors.unwrap().colors.iter().enumerate() {
-        println!("color{} = {}", x, color.clone().unwrap());
+    let theme = read_theme(scheme)?;
+    let mut colors = theme.slots();
+    colors.push((String::from("cursor"), theme.cursor));
+    for path in template::render_dir(templates, &output, &colors)? {
+        println!("Rendered {}", path.display());
     }
+    Ok(())
 }
 
-fn colors_from_image(file: &str, o_path: &str, rand: bool) -> Result<(), Error> {
-    let pallet_size = 16;
+// Number of colors in a colorscheme unless --colors says otherwise, color0 to color15
+const DEFAULT_PALLET_SIZE: u32 = 16;
+
+// Settings for turning an image into a colorscheme
+#[derive(Debug, Clone)]
+struct ThemeOptions {
+    // Shuffle the colors before writing them
+    random: bool,
+    // Number of colors asked from the quantizer, each one becomes a *colorN entry
+    pallet_size: u32,
+    histogram: HistogramOptions,
+    downsample: Downsample,
+    // How missing or duplicate colors are made up
+    completion: Completion,
+    // Colors closer than this delta E are merged, 0 keeps them all
+    dedupe: f64,
+    // Color difference used for merging duplicates and reporting drift
+    metric: DeltaE,
+    // How the darkest and brightest colors are found for *background and *foreground
+    luminance: Luminance,
+    // Contrast every color is made to have against the background, if any
+    contrast: Option<Contrast>,
+    // Which *colorN slot each color is written to
+    slots: Slots,
+    // Whether every color comes from the image or half are brighter versions of the others
+    scheme: Scheme,
+    // Dark or light theme, or picked from the image
+    mode: Mode,
+    // Changes made to the colors before they are written, in order
+    adjustments: Vec<Adjustment>,
+    // Print the colors that collide with each color vision deficiency
+    cvd_report: bool,
+    // Color vision deficiencies colliding colors are moved apart for
+    cvd_adapt: Vec<Deficiency>,
+    // Delta E below which two colors collide
+    cvd_threshold: f64,
+}
+
+impl Default for ThemeOptions {
+    fn default() -> ThemeOptions {
+        ThemeOptions {
+            random: false,
+            pallet_size: DEFAULT_PALLET_SIZE,
+            histogram: HistogramOptions::default(),
+            downsample: Downsample::default(),
+            completion: Completion::default(),
+            dedupe: DEFAULT_DEDUPE_THRESHOLD,
+            metric: DeltaE::default(),
+            luminance: Luminance::default(),
+            contrast: None,
+            slots: Slots::default(),
+            scheme: Scheme::default(),
+            mode: Mode::default(),
+            adjustments: Vec::new(),
+            cvd_report: false,
+            cvd_adapt: Vec::new(),
+            cvd_threshold: cvd::DEFAULT_CVD_THRESHOLD,
+        }
+    }
+}
+
+fn colors_from_image(
+    file: &str,
+    o_path: &str,
+    quantizer: &mut dyn Quantizer,
+    options: &ThemeOptions,
+) -> Result<(), Error> {
+    let pallet_size = options.pallet_size;
+    // With a derived scheme only the base colors come from the image
+    let base_size = options.scheme.base_size(pallet_size);
+    let hist_options = &options.histogram;
+    let downsample = &options.downsample;
     println!("Reading image {}", file);
 
-    let q_col = {
-        let img = image::load(BufReader::new(File::open(file).unwrap()), ImageFormat::Jpeg)
-            .unwrap()
+    // Auto mode looks at the histogram the pallet is found from
+    let pick_mode = |hist: &Histogram| match options.mode {
+        Mode::Auto => {
+            let median = hist.median_lightness();
+            let mode = options.mode.resolve(median);
+            println!(
+                "Median lightness of the image is {:.2}, using a {:?} theme",
+                median, mode
+            );
+            mode
+        }
+        mode => mode,
+    };
+    let (common_colors, mode) = {
+        let img = image::open(file)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", file, e)))?
             .to_rgba();
-        let data = img.into_vec();
+        // Auto picks its algorithm from the full image, so a downsampled copy and the drift
+        // reference are quantized the same way
+        quantizer.set_image_pixels(img.width() as usize * img.height() as usize);
+        match downsample.apply(&img) {
+            Some(small) => {
+                println!(
+                    "Downsampled {}x{} to {}x{}",
+                    img.width(),
+                    img.height(),
+                    small.width(),
+                    small.height()
+                );
+                let hist = Histogram::from_image(&small, hist_options);
+                let colors =
+                    distinct_pallet(quantizer, &hist, base_size, options.dedupe, options.metric);
 
-        q_image::MedianCut::from_pixel_vec(data.as_slice(), pallet_size)
+                if downsample.report_drift {
+                    let full_hist = Histogram::from_image(&img, hist_options);
+                    // Found the same way, so only the downsampling is measured
+                    let full_colors = distinct_pallet(
+                        quantizer,
+                        &full_hist,
+                        base_size,
+                        options.dedupe,
+                        options.metric,
+                    );
+                    let drift = preprocess::pallet_drift(&colors, &full_colors, options.metric);
+                    println!(
+                        "Pallet drift from full resolution: mean delta E {:.2}, max delta E {:.2}",
+                        drift.mean, drift.max
+                    );
+                }
+                (colors, pick_mode(&hist))
+            }
+            None => {
+                let hist = Histogram::from_image(&img, hist_options);
+                (
+                    distinct_pallet(quantizer, &hist, base_size, options.dedupe, options.metric),
+                    pick_mode(&hist),
+                )
+            }
+        }
     };
 
-    let common_colors = q_col.get_quantized_colors();
     let path = if !o_path.is_empty() {
         o_path
     } else {
         "./colorscheme"
     };
-    let mut output = File::create(path)?;
-
-    let mut all_colors = HashMap::new();
-
-    for x in 0..pallet_size {
-        let mut q = common_colors[x as usize];
 
-        // If number are too low add just enough to get them over 16.
-        // Messy fix for not getting format! to pad numbers below 16 with a zero in Hexadecimal.
-
-        if q.red <= 16 {
-            q.red += (16 - q.red) + 1;
-        }
-        if q.grn <= 16 {
-            q.grn += (16 - q.grn) + 1;
-        }
-        if q.blu <= 16 {
-            q.blu += (16 - q.blu) + 1;
-        }
-
-        // Find the luminance (brightness) of color. brighter = higher
-        let lum = calc_luminance(q.red, q.grn, q.blu);
-        let x_color_str = format!("*color{}: #{:X}{:X}{:X}", x, q.red, q.grn, q.blu);
-        // writeln!(output, "{}", x_color_str)?;
-        all_colors.insert(x_color_str, lum);
+    // The quantizer returns fewer colors than asked for when the image doesn't have that many, and
+    // low color images give colors that are hard to tell apart. Those are merged and the quantizer
+    // asked for more, whatever is still missing is made up. With completion turned off the
+    // colorscheme only gets a *colorN entry for each distinct color found.
+    let mut common_colors = complete_pallet(
+        &common_colors,
+        base_size as usize,
+        options.completion,
+        options.dedupe,
+        options.metric,
+    );
+    let made_up = common_colors.iter().filter(|c| c.count == 0).count();
+    if made_up > 0 {
+        println!(
+            "Only found {} distinct colors in {}, made up {} more",
+            common_colors.len() - made_up,
+            file,
+            made_up
+        );
     }
 
-    let mut lum_max = std::f64::MIN;
-    let mut lum_min = std::f64::MAX;
+    if common_colors.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "no colors found in image",
+        ));
+    }
 
-    for val in all_colors.values() {
-        if *val < lum_min {
-            lum_min = *val;
-        }
-        if *val > lum_max {
-            lum_max = *val;
+    if options.slots == Slots::Ansi {
+        common_colors = slots::assign_ansi(&common_colors);
+    }
+    if let Scheme::Derived { lightness, chroma } = options.scheme {
+        common_colors =
+            palette::derive_bright(&common_colors, pallet_size as usize, lightness, chroma);
+    }
+    adjust::adjust_pallet(&mut common_colors, &options.adjustments);
+    // Shuffling only moves the colors between the *colorN slots, background and foreground are picked
+    // by luminance afterwards. Done before anything reports slots so the names match the file.
+    if options.random {
+        common_colors.shuffle(&mut thread_rng());
+    }
+    if !options.cvd_adapt.is_empty() {
+        let changed = cvd::adapt(
+            &mut common_colors,
+            &options.cvd_adapt,
+            options.metric,
+            options.cvd_threshold,
+        );
+        if !changed.is_empty() {
+            let slots: Vec<String> = changed.iter().map(|i| format!("color{}", i)).collect();
+            println!(
+                "Changed the lightness of {} to tell them apart with color blindness",
+                slots.join(", ")
+            );
         }
     }
+    if common_colors.len() < pallet_size as usize {
+        println!(
+            "Only found {} of {} colors in {}, writing {} colors",
+            common_colors.len(),
+            pallet_size,
+            file,
+            common_colors.len()
+        );
+    }
 
-    // Find and get the appropriate matching max and min values and their key
-    // approx_eq! is from the float-cmp crate, makes it so it compiles with cargo clippy
-    let fg = all_colors.iter().find_map(|(key, &val)| {
-        if approx_eq!(f64, val, lum_max, ulps = 5) {
-            Some(key)
-        } else {
-            None
+    // The darkest color becomes the background, or the lightest in a light theme. Light themes get a
+    // light enough background and their colors darkened to be readable on it.
+    let background = {
+        let by_luminance = |&a: &usize, &b: &usize| {
+            let lum_a = options.luminance.of(common_colors[a].color());
+            let lum_b = options.luminance.of(common_colors[b].color());
+            lum_a.partial_cmp(&lum_b).unwrap()
+        };
+        match mode {
+            Mode::Light => (0..common_colors.len()).max_by(by_luminance),
+            _ => (0..common_colors.len()).min_by(by_luminance),
+        }
+        .unwrap()
+    };
+    let target = match mode {
+        Mode::Light => {
+            if contrast::lighten_background(&mut common_colors[background]) {
+                println!("Lightened color{} to use as a light background", background);
+            }
+            Some(options.contrast.unwrap_or(contrast::LIGHT_MODE_CONTRAST))
         }
-    });
-    let bg = all_colors.iter().find_map(|(key, &val)| {
-        if approx_eq!(f64, val, lum_min, ulps = 5) {
-            Some(key)
+        _ => options.contrast,
+    };
+    if let Some(target) = target {
+        let changed = contrast::enforce(&mut common_colors, background, target);
+        if changed.is_empty() {
+            println!(
+                "Every color meets {} against the background",
+                target.describe()
+            );
         } else {
-            None
+            let slots: Vec<String> = changed.iter().map(|i| format!("color{}", i)).collect();
+            println!(
+                "Changed the lightness of {} to meet {} against the background",
+                slots.join(", "),
+                target.describe()
+            );
         }
-    });
+    }
 
-    if rand {
-        let rand_colors: Vec<String> = shuffle_colors(&all_colors);
-        for c in rand_colors {
-            writeln!(output, "{}", c)?;
-        }
-    } else {
-        for c in all_colors.keys() {
-            writeln!(output, "{}", c)?;
+    // Lightening the background and enforcing contrast change lightness too, and can undo --cvd-adapt
+    if options.cvd_report {
+        let names: Vec<String> = (0..common_colors.len())
+            .map(|i| format!("color{}", i))
+            .collect();
+        print_collisions(
+            &names,
+            &common_colors,
+            options.metric,
+            options.cvd_threshold,
+        );
+    } else if target.is_some() {
+        for &deficiency in &options.cvd_adapt {
+            for (i, j, _) in cvd::collisions(
+                &common_colors,
+                deficiency,
+                options.metric,
+                options.cvd_threshold,
+            ) {
+                println!(
+                    "color{} and color{} collide again for {} after enforcing contrast",
+                    i,
+                    j,
+                    deficiency.name()
+                );
+            }
         }
     }
 
-    let bg_color = bg.unwrap().as_str();
-    let fg_color = fg.unwrap().as_str();
-
-    // Confirm what was found is one of our colors
-    assert!(all_colors.contains_key(bg_color));
-    assert!(all_colors.contains_key(fg_color));
+    // The brightest color becomes the foreground, or the darkest in a light theme
+    let foreground = {
+        let by_luminance = |&a: &usize, &b: &usize| {
+            let lum_a = options.luminance.of(common_colors[a].color());
+            let lum_b = options.luminance.of(common_colors[b].color());
+            lum_a.partial_cmp(&lum_b).unwrap()
+        };
+        match mode {
+            Mode::Light => (0..common_colors.len()).min_by(by_luminance),
+            _ => (0..common_colors.len()).max_by(by_luminance),
+        }
+        .unwrap()
+    };
+    let mut theme = Theme::new(common_colors, background, foreground);
+    theme.metadata = vec![
+        (String::from("image"), file.to_string()),
+        (String::from("mode"), format!("{:?}", mode).to_lowercase()),
+        (
+            String::from("luminance"),
+            options.luminance.name().to_string(),
+        ),
+    ];
 
-    let bg_str: Vec<&str> = bg_color.split(' ').collect();
-    let fg_str: Vec<&str> = fg_color.split(' ').collect();
+    let text = theme.to_xresources();
+    write(path, &text)?;
+    println!("This is your generated colorscheme, saved in {}", path);
+    print!("{}", text);
 
-    // Construct string for file that has the brightest and darkest values
-    let bg_file_string = format!("*background: {}", bg_str[1]);
-    let fg_file_string = format!("*foreground: {}", fg_str[1]);
+    Ok(())
+}
 
-    // Write string to bottom of colorscheme file
-    writeln!(output, "{}", bg_file_string)?;
-    writeln!(output, "{}", fg_file_string)?;
+// Reads a color (#rrggbb, rrggbb, hsl(h, s%, l%) or hsv(h, s%, v%)) or every slot of a colorscheme file. Files come first, so a
+// file named like a color, say cafe00, is still read as a file.
+fn read_colors(arg: &str) -> Result<Vec<(String, ColorChannel)>, Error> {
+    if !Path::new(arg).exists() {
+        if let Some(color) = parse_color(arg) {
+            return Ok(vec![(arg.to_string(), color)]);
+        }
+    }
+    // Only the slots that are there, partial files are fine here
+    let slots = theme::read_slots(&read_to_string(arg)?);
+    if slots.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{}: no colors", arg),
+        ));
+    }
+    Ok(slots)
+}
 
-    let input = File::open(path)?;
-    let buffered = BufReader::new(input);
+fn read_theme(file: &str) -> Result<Theme, Error> {
+    Theme::from_xresources(&read_to_string(file)?)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", file, e)))
+}
 
-    println!("This is your generated colorscheme, saved in {}", o_path);
-    for line in buffered.lines() {
-        println!("{}", line?);
+// The distance subcommand, lists every pair of colors from the closest to the furthest
+fn print_distances(matches: &ArgMatches) -> Result<(), Error> {
+    let metric = matches
+        .value_of("metric")
+        .map_or(DeltaE::default(), |m| DeltaE::from_name(m).unwrap());
+    let mut names = Vec::new();
+    let mut colors = Vec::new();
+    for arg in matches.values_of("colors").unwrap() {
+        for (name, color) in read_colors(arg)? {
+            names.push(name);
+            colors.push(color);
+        }
+    }
+    if colors.len() < 2 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "need at least two colors to compare",
+        ));
     }
 
+    println!("{:?} delta E between {} colors\n", metric, colors.len());
+    for (i, j, distance) in delta_e::pairwise(&colors, metric) {
+        println!("{:>8.2}  {} {}", distance, names[i], names[j]);
+    }
     Ok(())
 }
 
-// TODO implement an iterator method to ColorChannel that takes this as a closure?
-fn calc_luminance(r: u8, g: u8, b: u8) -> f64 {
-    (r as f64 * 0.299 + g as f64 * 0.587 + b as f64 * 0.114) / 256.0
+// Lists the pairs of colors that collide with each color vision deficiency, closest first
+fn print_collisions(names: &[String], colors: &[ColorChannel], metric: DeltaE, threshold: f64) {
+    for &deficiency in cvd::DEFICIENCIES.iter() {
+        let pairs = cvd::collisions(colors, deficiency, metric, threshold);
+        if pairs.is_empty() {
+            println!(
+                "{}: no colors closer than delta E {}",
+                deficiency.name(),
+                threshold
+            );
+            continue;
+        }
+        println!(
+            "{}: {} pairs closer than delta E {}",
+            deficiency.name(),
+            pairs.len(),
+            threshold
+        );
+        for (i, j, distance) in pairs {
+            println!("{:>8.2}  {} {}", distance, names[i], names[j]);
+        }
+    }
+}
+
+// The cvd subcommand, the collision report for existing colors or colorscheme files
+fn check_cvd(matches: &ArgMatches) -> Result<(), Error> {
+    let metric = matches
+        .value_of("metric")
+        .map_or(DeltaE::default(), |m| DeltaE::from_name(m).unwrap());
+    let threshold = matches
+        .value_of("threshold")
+        .map_or(cvd::DEFAULT_CVD_THRESHOLD, |t| t.parse().unwrap());
+    let mut names = Vec::new();
+    let mut colors = Vec::new();
+    for arg in matches.values_of("colors").unwrap() {
+        for (name, color) in read_colors(arg)? {
+            names.push(name);
+            colors.push(color);
+        }
+    }
+    if colors.len() < 2 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "need at least two colors to compare",
+        ));
+    }
+
+    print_collisions(&names, &colors, metric, threshold);
+    Ok(())
 }
@@ -0,0 +1,288 @@
+use crate::color::Color;
+use crate::delta_e::DeltaE;
+use crate::q_image::{ColorChannel, Histogram, Quantizer};
+use std::cmp::Reverse;
+
+// Colors closer than this delta E are hard to tell apart and count as duplicates
+pub const DEFAULT_DEDUPE_THRESHOLD: f64 = 2.3;
+
+// How many times the quantizer is asked for more colors before the rest are made up
+const MAX_REQUANTIZE: usize = 4;
+
+// How the missing colors of a pallet are made up when the image doesn't have enough distinct ones
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Completion {
+    // Leave the pallet short
+    None,
+    // Blend pairs of existing colors
+    Interpolate,
+    // Lighter and darker versions of the existing colors
+    #[default]
+    Lightness,
+    // The existing colors with their hue rotated, greys are tinted first so they have a hue to rotate
+    Hue,
+}
+
+// Names accepted by Completion::from_name(), used for the --complete option
+pub const COMPLETIONS: [&str; 4] = ["none", "interpolate", "lightness", "hue"];
+
+impl Completion {
+    pub fn from_name(name: &str) -> Option<Completion> {
+        match name {
+            "none" => Some(Completion::None),
+            "interpolate" => Some(Completion::Interpolate),
+            "lightness" => Some(Completion::Lightness),
+            "hue" => Some(Completion::Hue),
+            _ => None,
+        }
+    }
+}
+
+// Merges colors closer than threshold (delta E) into the more common color they are close to, the
+// counts are added together. A threshold of 0 keeps every color.
+pub fn remove_duplicates(
+    colors: &[ColorChannel],
+    threshold: f64,
+    metric: DeltaE,
+) -> Vec<ColorChannel> {
+    let mut sorted = colors.to_vec();
+    sorted.sort_by_key(|c| Reverse(c.count));
+
+    let mut kept: Vec<(ColorChannel, [f64; 3])> = Vec::with_capacity(colors.len());
+    for c in sorted {
+        let coords = metric.coords(&c);
+        match kept
+            .iter_mut()
+            .find(|(_, k)| metric.distance(k, &coords) < threshold)
+        {
+            Some((k, _)) => k.count += c.count,
+            None => kept.push((c, coords)),
+        }
+    }
+    let mut kept: Vec<ColorChannel> = kept.into_iter().map(|(c, _)| c).collect();
+    kept.sort_by_key(|c| Reverse(c.count));
+    kept
+}
+
+// Quantizes the histogram and merges the duplicates, asking the quantizer for more colors while
+// there are fewer than size distinct ones left. Stops when the quantizer has no more colors to give,
+// so the pallet can still come back short for images with only a few colors.
+pub fn distinct_pallet(
+    quantizer: &mut dyn Quantizer,
+    hist: &Histogram,
+    size: u32,
+    threshold: f64,
+    metric: DeltaE,
+) -> Vec<ColorChannel> {
+    let mut asked = size;
+    let mut colors = quantizer.quantize_histogram(hist, asked);
+    let mut pallet = remove_duplicates(&colors, threshold, metric);
+    for _ in 0..MAX_REQUANTIZE {
+        if pallet.len() >= size as usize || colors.len() < asked as usize {
+            break;
+        }
+        asked += size - pallet.len() as u32;
+        colors = quantizer.quantize_histogram(hist, asked);
+        let more = remove_duplicates(&colors, threshold, metric);
+        if more.len() <= pallet.len() {
+            break;
+        }
+        pallet = more;
+    }
+    pallet.truncate(size as usize);
+    pallet
+}
+
+// New colors the strategy makes from the last color of the pallet, together with the ones before it
+fn candidates(pallet: &[ColorChannel], strategy: Completion) -> Vec<Color> {
+    let mut found = Vec::new();
+    let newest = match pallet.last() {
+        Some(c) => c.color(),
+        None => return found,
+    };
+    match strategy {
+        Completion::None => {}
+        Completion::Interpolate => {
+            let a = newest.to_oklab();
+            for b in &pallet[..pallet.len() - 1] {
+                let b = b.color().to_oklab();
+                found.push(Color::from_oklab([
+                    (a[0] + b[0]) / 2.0,
+                    (a[1] + b[1]) / 2.0,
+                    (a[2] + b[2]) / 2.0,
+                ]));
+            }
+        }
+        Completion::Lightness => {
+            let [l, a, b] = newest.to_oklab();
+            for step in 1..=8 {
+                let shift = step as f64 * 0.05;
+                found.push(Color::from_oklab([(l + shift).min(1.0), a, b]));
+                found.push(Color::from_oklab([(l - shift).max(0.0), a, b]));
+            }
+        }
+        Completion::Hue => {
+            // Greys have no hue, give them a tint to rotate
+            let [l, chroma, hue] = newest.to_oklch();
+            for step in 1..12 {
+                found.push(Color::from_oklch([
+                    l,
+                    chroma.max(0.08),
+                    hue + step as f64 * 30.0,
+                ]));
+            }
+        }
+    }
+    found
+}
+
+// Colors a strategy could add to the pallet, each with its coordinates for the metric and the delta E
+// to the closest color of the pallet. Kept up to date as the pallet grows so every candidate is only
+// compared with each pallet color once.
+struct Candidates {
+    strategy: Completion,
+    metric: DeltaE,
+    colors: Vec<(ColorChannel, [f64; 3], f64)>,
+}
+
+impl Candidates {
+    fn new(strategy: Completion, metric: DeltaE) -> Candidates {
+        Candidates {
+            strategy,
+            metric,
+            colors: Vec::new(),
+        }
+    }
+
+    // Called after each color is added to the pallet, coords holds the coordinates of the pallet
+    fn grow(&mut self, pallet: &[ColorChannel], coords: &[[f64; 3]]) {
+        let metric = self.metric;
+        let newest = coords[coords.len() - 1];
+        for (_, c, nearest) in self.colors.iter_mut() {
+            *nearest = nearest.min(metric.distance(c, &newest));
+        }
+        for color in candidates(pallet, self.strategy) {
+            // Compare what will actually be written, after rounding to sRGB
+            let made_up = ColorChannel::from_color(color, 0);
+            let c = metric.coords(&made_up);
+            let nearest = coords
+                .iter()
+                .map(|p| metric.distance(p, &c))
+                .fold(f64::MAX, f64::min);
+            self.colors.push((made_up, c, nearest));
+        }
+    }
+
+    // Index of the candidate furthest from every pallet color, and its delta E to the closest
+    fn farthest(&self) -> Option<(usize, f64)> {
+        let mut best: Option<(usize, f64)> = None;
+        for (i, (_, _, nearest)) in self.colors.iter().enumerate() {
+            if best.is_none_or(|(_, d)| *nearest > d) {
+                best = Some((i, *nearest));
+            }
+        }
+        best
+    }
+
+    fn take(&mut self, i: usize) -> ColorChannel {
+        self.colors.swap_remove(i).0
+    }
+}
+
+// Fills the pallet up to size colors with colors made up by the strategy, after merging duplicates.
+// Each new color is the candidate furthest from every color already in the pallet, made up colors
+// have a count of zero so they sort after the colors from the image. With Completion::None the pallet
+// only loses its duplicates. An empty pallet is returned as it is.
+pub fn complete_pallet(
+    colors: &[ColorChannel],
+    size: usize,
+    strategy: Completion,
+    threshold: f64,
+    metric: DeltaE,
+) -> Vec<ColorChannel> {
+    let mut pallet = remove_duplicates(colors, threshold, metric);
+    if pallet.is_empty() || strategy == Completion::None {
+        return pallet;
+    }
+
+    // Interpolating needs two colors and a strategy can run out of distinct colors to make, fall
+    // back to changing the lightness when it does
+    let mut made = Candidates::new(strategy, metric);
+    let mut lighter = Candidates::new(Completion::Lightness, metric);
+    let fall_back = strategy != Completion::Lightness;
+    let mut coords = Vec::with_capacity(size);
+    for i in 0..pallet.len() {
+        coords.push(metric.coords(&pallet[i]));
+        made.grow(&pallet[..=i], &coords);
+        if fall_back {
+            lighter.grow(&pallet[..=i], &coords);
+        }
+    }
+
+    while pallet.len() < size {
+        let best = made.farthest();
+        let color = match (best, lighter.farthest()) {
+            (b, Some((i, l))) if fall_back && b.is_none_or(|(_, d)| d < threshold && l > d) => {
+                lighter.take(i)
+            }
+            (Some((i, _)), _) => made.take(i),
+            _ => break,
+        };
+        coords.push(metric.coords(&color));
+        pallet.push(color);
+        made.grow(&pallet, &coords);
+        if fall_back {
+            lighter.grow(&pallet, &coords);
+        }
+    }
+    pallet
+}
+
+// How the colorscheme is filled from the image
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Scheme {
+    // Every color comes straight from the image
+    #[default]
+    Raw,
+    // Half the colors come from the image and the other half are brighter versions of them, the way
+    // color8-15 brighten color0-7 in terminal schemes. Lightness is added to OKLab L and chroma is
+    // multiplied.
+    Derived {
+        lightness: f64,
+        chroma: f64,
+    },
+}
+
+// Names accepted for the --scheme option
+pub const SCHEMES: [&str; 2] = ["raw", "derived"];
+
+pub const DEFAULT_BRIGHT_LIGHTNESS: f64 = 0.1;
+pub const DEFAULT_BRIGHT_CHROMA: f64 = 1.15;
+
+impl Scheme {
+    // How many colors to take from the image for a colorscheme of size colors
+    pub fn base_size(self, size: u32) -> u32 {
+        match self {
+            Scheme::Raw => size,
+            Scheme::Derived { .. } => size.div_ceil(2),
+        }
+    }
+}
+
+// Adds a brighter version of the base colors after them, in the same order, until there are size
+// colors. The hue is kept and the chroma lowered where the brighter color wouldn't fit in sRGB.
+// Brighter colors have a count of zero as they don't come from the image.
+pub fn derive_bright(
+    base: &[ColorChannel],
+    size: usize,
+    lightness: f64,
+    chroma: f64,
+) -> Vec<ColorChannel> {
+    let mut pallet = base.to_vec();
+    for c in base.iter().take(size.saturating_sub(base.len())) {
+        let [l, c_base, hue] = c.color().to_oklch();
+        let bright = Color::from_oklch_in_gamut([(l + lightness).min(1.0), c_base * chroma, hue]);
+        pallet.push(ColorChannel::from_color(bright, 0));
+    }
+    pallet
+}
@@ -0,0 +1,123 @@
+use crate::delta_e::DeltaE;
+use crate::q_image::ColorChannel;
+use image::imageops::{self, FilterType};
+use image::RgbaImage;
+
+// How much to shrink the image before counting its colors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    // Use every pixel of the image
+    Full,
+    // Shrink the image until it has at most this many pixels
+    MaxPixels(u32),
+    // Scale both sides of the image by this factor, 0.0 to 1.0
+    Scale(f64),
+}
+
+// Settings for the resize done before quantizing
+#[derive(Debug, Clone, Copy)]
+pub struct Downsample {
+    pub target: Target,
+    pub filter: FilterType,
+    // Quantize the full size image as well and report how far the pallets are apart
+    pub report_drift: bool,
+}
+
+impl Default for Downsample {
+    fn default() -> Downsample {
+        Downsample {
+            target: Target::Full,
+            filter: FilterType::Triangle,
+            report_drift: false,
+        }
+    }
+}
+
+// Names accepted by filter_from_name(), used for the --filter option
+pub const FILTERS: [&str; 5] = ["nearest", "triangle", "catmullrom", "gaussian", "lanczos3"];
+
+pub fn filter_from_name(name: &str) -> Option<FilterType> {
+    match name {
+        "nearest" => Some(FilterType::Nearest),
+        "triangle" => Some(FilterType::Triangle),
+        "catmullrom" => Some(FilterType::CatmullRom),
+        "gaussian" => Some(FilterType::Gaussian),
+        "lanczos3" => Some(FilterType::Lanczos3),
+        _ => None,
+    }
+}
+
+impl Downsample {
+    // Size the image will be resized to, None if it is already small enough
+    pub fn target_size(&self, width: u32, height: u32) -> Option<(u32, u32)> {
+        let scale = match self.target {
+            Target::Full => return None,
+            Target::MaxPixels(max) => {
+                let pixels = width as f64 * height as f64;
+                if pixels <= max as f64 {
+                    return None;
+                }
+                (max as f64 / pixels).sqrt()
+            }
+            Target::Scale(scale) if scale >= 1.0 => return None,
+            Target::Scale(scale) => scale,
+        };
+        // Rounded down so MaxPixels is never exceeded
+        let mut w = ((width as f64 * scale).floor() as u32).max(1);
+        let mut h = ((height as f64 * scale).floor() as u32).max(1);
+        // A very thin image has its short side raised to 1 pixel, so the long side gives way instead
+        if let Target::MaxPixels(max) = self.target {
+            if w as u64 * h as u64 > max as u64 {
+                if w >= h {
+                    w = (max / h).max(1);
+                } else {
+                    h = (max / w).max(1);
+                }
+            }
+        }
+        Some((w, h))
+    }
+
+    // Returns the resized image, or None when the image is used as it is
+    pub fn apply(&self, img: &RgbaImage) -> Option<RgbaImage> {
+        let (w, h) = self.target_size(img.width(), img.height())?;
+        Some(imageops::resize(img, w, h, self.filter))
+    }
+}
+
+// How far a pallet is from a reference pallet, in delta E
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Drift {
+    // Average distance from each color to the closest reference color, weighted by pixel count
+    pub mean: f64,
+    // Largest distance from any color to the closest reference color
+    pub max: f64,
+}
+
+// Compares the pallet of the downsampled image with the pallet of the full size image.
+// A delta E below 1 is not visible, below 5 only noticeable side by side.
+pub fn pallet_drift(pallet: &[ColorChannel], reference: &[ColorChannel], metric: DeltaE) -> Drift {
+    let reference: Vec<[f64; 3]> = reference.iter().map(|c| metric.coords(c)).collect();
+
+    let mut sum = 0.0;
+    let mut weight = 0.0;
+    let mut max: f64 = 0.0;
+    for c in pallet {
+        let coords = metric.coords(c);
+        let nearest = reference
+            .iter()
+            .map(|r| metric.distance(&coords, r))
+            .fold(f64::MAX, f64::min);
+        if nearest == f64::MAX {
+            continue;
+        }
+        sum += nearest * c.count as f64;
+        weight += c.count as f64;
+        max = max.max(nearest);
+    }
+
+    Drift {
+        mean: if weight > 0.0 { sum / weight } else { 0.0 },
+        max,
+    }
+}
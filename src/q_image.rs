@@ -1,23 +1,38 @@
-use std::convert::TryInto;
+use crate::color::{self, ColorSpace};
+use image::RgbaImage;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::thread;
 
 // Data structs used based from Java implementation provided in README
 
+// A histogram color placed in the color space median cut works in, along with its pixel count
+#[derive(Debug, Clone, Copy, Default)]
+struct ColorPoint {
+    pos: [f64; 3],
+    count: usize,
+}
+
+// The bounds are kept per axis of the color space, for CIELAB and OKLab the red, green and blue
+// bounds hold L, a and b instead.
 #[derive(Debug, Clone, Copy, Default)]
 struct ColorBucket {
     lower: usize,
     upper: usize,
     level: isize,
     count: usize,
-    rmin: i32,
-    rmax: i32,
-    gmin: i32,
-    gmax: i32,
-    bmin: i32,
-    bmax: i32,
+    rmin: f64,
+    rmax: f64,
+    gmin: f64,
+    gmax: f64,
+    bmin: f64,
+    bmax: f64,
+    // Sum of the squared distances of the colors from their mean, population times variance
+    sq_error: f64,
 }
 
 impl ColorBucket {
-    fn new(lower: usize, upper: usize, level: isize, colors: &[ColorChannel]) -> ColorBucket {
+    fn new(lower: usize, upper: usize, level: isize, colors: &[ColorPoint]) -> ColorBucket {
         let mut bucket = ColorBucket {
             lower,
             upper,
@@ -33,12 +48,12 @@ impl ColorBucket {
 
     // Reset values for use in the update_bounds() function
     fn reset_dimensions(&mut self) {
-        self.rmin = 255;
-        self.rmax = 0;
-        self.gmin = 255;
-        self.gmax = 0;
-        self.bmin = 255;
-        self.bmax = 0;
+        self.rmin = f64::MAX;
+        self.rmax = f64::MIN;
+        self.gmin = f64::MAX;
+        self.gmax = f64::MIN;
+        self.bmin = f64::MAX;
+        self.bmax = f64::MIN;
         self.count = 0;
     }
 
@@ -47,7 +62,7 @@ impl ColorBucket {
     }
 
     // Sets internal color value to whatever is the largest of that and the provided argument val. Also matches the colortype to decide what channel to compare.
-    fn larger(&mut self, in_color: i32, c_type: Color) {
+    fn larger(&mut self, in_color: f64, c_type: Color) {
         match c_type {
             Color::Red => {
                 if in_color > self.rmax {
@@ -77,21 +92,42 @@ impl ColorBucket {
     }
 
     // Updates bounds of colors in bucket
-    fn update_bounds(&mut self, colors: &[ColorChannel]) {
+    fn update_bounds(&mut self, colors: &[ColorPoint]) {
         self.reset_dimensions();
+        let mut sum = [0.0; 3];
+        let mut sq_sum = 0.0;
 
         for c in colors.iter().take(self.upper).skip(self.lower) {
-            // let color = colors[i];
             self.count += c.count;
 
-            self.larger(c.red as i32, Color::Red);
-            self.larger(c.grn as i32, Color::Green);
-            self.larger(c.blu as i32, Color::Blue);
+            self.larger(c.pos[0], Color::Red);
+            self.larger(c.pos[1], Color::Green);
+            self.larger(c.pos[2], Color::Blue);
+
+            let n = c.count as f64;
+            for (s, p) in sum.iter_mut().zip(c.pos.iter()) {
+                *s += n * p;
+                sq_sum += n * p * p;
+            }
         }
+
+        self.sq_error = if self.count == 0 {
+            0.0
+        } else {
+            sq_sum - sum.iter().map(|s| s * s).sum::<f64>() / self.count as f64
+        };
+    }
+
+    // Population times the volume of the bucket. A step is added to every side so flat buckets still count.
+    fn population_volume(&self, step: f64) -> f64 {
+        let volume = (self.rmax - self.rmin + step)
+            * (self.gmax - self.gmin + step)
+            * (self.bmax - self.bmin + step);
+        self.count as f64 * volume
     }
 
     // Grabs Median of the longest color dimension and use it to find where the next split should be.
-    fn split_box(&mut self, colors: &mut Vec<ColorChannel>) -> Option<ColorBucket> {
+    fn split_box(&mut self, colors: &mut [ColorPoint]) -> Option<ColorBucket> {
         if self.color_count() < 2 {
             None
         } else {
@@ -123,14 +159,11 @@ impl ColorBucket {
         }
     }
 
-    fn find_median(&self, longest_dimension: Color, colors: &mut Vec<ColorChannel>) -> usize {
+    fn find_median(&self, longest_dimension: Color, colors: &mut [ColorPoint]) -> usize {
         // sort color in this box along longest_dimension
         // By continuing to do this until the pallet is created, we try and seperate off distinctive colors by moving them to the top and splitting them off
-        match longest_dimension {
-            Color::Red => colors[self.lower..=self.upper].sort_by(|x, y| x.red.cmp(&y.red)),
-            Color::Green => colors[self.lower..=self.upper].sort_by(|x, y| x.grn.cmp(&y.grn)),
-            Color::Blue => colors[self.lower..=self.upper].sort_by(|x, y| x.blu.cmp(&y.blu)),
-        }
+        let axis = longest_dimension as usize;
+        colors[self.lower..=self.upper].sort_by(|x, y| x.pos[axis].total_cmp(&y.pos[axis]));
 
         // iterate through and find the appropriate median to return by using the color count of each channel to increment the pixel number
         let half = self.count / 2;
@@ -146,26 +179,25 @@ impl ColorBucket {
         self.lower
     }
 
-    // Returns a new ColorChannel containing the acerage values of all the colors in the provided channel
-    fn avg_color(&self, colors: &mut Vec<ColorChannel>) -> ColorChannel {
-        let mut r_sum = 0;
-        let mut g_sum = 0;
-        let mut b_sum = 0;
+    // Returns a new ColorChannel containing the average of all the colors in the bucket, averaged in
+    // the color space and converted back to rgb
+    fn avg_color(&self, colors: &[ColorPoint], space: ColorSpace) -> ColorChannel {
+        let mut sum = [0.0; 3];
         let mut n: usize = 0;
-        for channel in colors.iter().take(self.upper).skip(self.lower) {
-            let c = channel.count;
-            r_sum += c * channel.red as usize;
-            g_sum += c * channel.grn as usize;
-            b_sum += c * channel.blu as usize;
-            n += c;
-        }
-        let avg_r = (r_sum as f64 / n as f64) as u8;
-        let avg_g = (g_sum as f64 / n as f64) as u8;
-        let avg_b = (b_sum as f64 / n as f64) as u8;
+        for point in colors.iter().take(self.upper).skip(self.lower) {
+            let c = point.count as f64;
+            for (s, p) in sum.iter_mut().zip(point.pos.iter()) {
+                *s += c * p;
+            }
+            n += point.count;
+        }
+        let avg = [sum[0] / n as f64, sum[1] / n as f64, sum[2] / n as f64];
+        let [avg_r, avg_g, avg_b] = space.to_rgb(avg);
         ColorChannel::new_colors(avg_r, avg_g, avg_b, n)
     }
 }
 
+// Channels of a color, also used to name the axis of a color space
 #[derive(Debug, Clone, Copy)]
 enum Color {
     Red,
@@ -173,6 +205,123 @@ enum Color {
     Blue,
 }
 
+// Pixels are read as [red, green, blue, alpha], the byte order of image::RgbaImage
+pub const RED: usize = 0;
+pub const GREEN: usize = 1;
+pub const BLUE: usize = 2;
+pub const ALPHA: usize = 3;
+
+// Pixels with less alpha than this are left out of the histogram unless told otherwise
+pub const DEFAULT_ALPHA_THRESHOLD: u8 = 128;
+
+// How transparent pixels are counted when building a histogram
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AlphaMode {
+    // Pixels with alpha below the threshold are skipped, the rest count as one pixel.
+    // A threshold of 0 counts every pixel.
+    Threshold(u8),
+    // Every pixel counts by its alpha in 255ths of a pixel, a fully opaque pixel as 255 and a half
+    // transparent one as about 128, so no color that is there is ever rounded away
+    Weighted,
+}
+
+impl Default for AlphaMode {
+    fn default() -> AlphaMode {
+        AlphaMode::Threshold(DEFAULT_ALPHA_THRESHOLD)
+    }
+}
+
+// How the colors of a histogram are counted
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum HistogramMode {
+    // Every distinct color is counted in a hash map
+    #[default]
+    Exact,
+    // Colors are reduced to 5 bits per channel and counted in a table of 32768 cells, each cell
+    // becomes the average of the colors that fell in it. Faster, at the cost of some precision.
+    Rgb555,
+    // The original implementation, copies and sorts the pixels then counts runs of equal colors
+    Sorted,
+}
+
+// Names accepted by HistogramMode::from_name(), used for the --histogram option
+pub const HISTOGRAM_MODES: [&str; 3] = ["exact", "rgb555", "sorted"];
+
+impl HistogramMode {
+    pub fn from_name(name: &str) -> Option<HistogramMode> {
+        match name {
+            "exact" => Some(HistogramMode::Exact),
+            "rgb555" => Some(HistogramMode::Rgb555),
+            "sorted" => Some(HistogramMode::Sorted),
+            _ => None,
+        }
+    }
+}
+
+// Settings for building a histogram out of pixels
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HistogramOptions {
+    pub alpha: AlphaMode,
+    pub mode: HistogramMode,
+    // Threads used to count the pixels, 0 uses one per core
+    pub threads: usize,
+}
+
+// Fewest pixels worth handing to a thread of their own
+const MIN_PIXELS_PER_THREAD: usize = 1 << 16;
+
+// Splits the flat pixel slice into one equal chunk per thread, which need not line up with rows,
+// and runs count on each chunk in parallel.
+// The results are returned in the order of the chunks.
+fn count_in_parallel<T, F>(pixels: &[[u8; 4]], threads: usize, count: F) -> Vec<T>
+where
+    T: Send,
+    F: Fn(&[[u8; 4]]) -> T + Sync,
+{
+    let threads = if threads == 0 {
+        thread::available_parallelism().map_or(1, |n| n.get())
+    } else {
+        threads
+    };
+    let threads = threads.min(pixels.len() / MIN_PIXELS_PER_THREAD).max(1);
+    if threads == 1 {
+        return vec![count(pixels)];
+    }
+
+    let chunk_size = pixels.len().div_ceil(threads);
+    let count = &count;
+    thread::scope(|scope| {
+        let handles: Vec<_> = pixels
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || count(chunk)))
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| h.join().expect("histogram thread panicked"))
+            .collect()
+    })
+}
+
+// How much a pixel counts in the histogram, None when it is skipped. Weighted pixels count in 255ths.
+fn pixel_weight(p: &[u8; 4], alpha: AlphaMode) -> Option<usize> {
+    match alpha {
+        AlphaMode::Threshold(min) if p[ALPHA] < min => None,
+        AlphaMode::Threshold(_) => Some(1),
+        AlphaMode::Weighted if p[ALPHA] == 0 => None,
+        AlphaMode::Weighted => Some(p[ALPHA] as usize),
+    }
+}
+
+// Packs a color into a u32 as 0x00RRGGBB, the layout of ColorChannel::rgb and of the histogram colors
+pub fn pack_rgb(red: u8, grn: u8, blu: u8) -> u32 {
+    (red as u32) << 16 | (grn as u32) << 8 | blu as u32
+}
+
+// Views the pixel buffer of an image as rgba pixels without copying it
+pub fn rgba_pixels(img: &RgbaImage) -> &[[u8; 4]] {
+    bytemuck::cast_slice(img)
+}
+
 #[derive(Debug, Clone, PartialEq, Copy, Default)]
 pub struct ColorChannel {
     pub rgb: u32,
@@ -186,133 +335,339 @@ impl ColorChannel {
     fn new_rgb(rgb: u32, count: usize) -> ColorChannel {
         ColorChannel {
             rgb: (rgb & 0x00FF_FFFF),
-            blu: ((rgb & 0x00FF_0000) >> 16) as u8,
+            red: ((rgb & 0x00FF_0000) >> 16) as u8,
             grn: ((rgb & 0xFF00) >> 8) as u8,
-            red: (rgb & 0xFF) as u8,
+            blu: (rgb & 0xFF) as u8,
             count,
         }
     }
 
-    fn new_colors(red: u8, grn: u8, blu: u8, count: usize) -> ColorChannel {
+    pub fn new_colors(red: u8, grn: u8, blu: u8, count: usize) -> ColorChannel {
         ColorChannel {
-            rgb: ((red as u32 & 0xff) << 16) | ((grn as u32 & 0xff) << 8) | blu as u32 & 0xff,
+            rgb: pack_rgb(red, grn, blu),
             red,
             grn,
             blu,
             count,
         }
     }
+
+    pub fn from_color(color: color::Color, count: usize) -> ColorChannel {
+        let [red, grn, blu] = color.to_rgb8();
+        ColorChannel::new_colors(red, grn, blu, count)
+    }
+
+    pub fn color(&self) -> color::Color {
+        color::Color::from_rgb8(self.red, self.grn, self.blu)
+    }
 }
 
-struct Histogram {
+pub struct Histogram {
     color_vec: Vec<u32>,
     count_vec: Vec<usize>,
+    pixels: usize,
 }
 
 impl Histogram {
     pub fn new(colors: Vec<u32>, counts: Vec<usize>) -> Histogram {
+        let pixels = counts.iter().sum();
         Histogram {
             color_vec: colors,
             count_vec: counts,
+            pixels,
+        }
+    }
+
+    pub fn from_image(img: &RgbaImage, options: &HistogramOptions) -> Histogram {
+        Histogram::new_pixels(rgba_pixels(img), options)
+    }
+
+    // Build a histogram from rgba pixels. Each pixel is packed with pack_rgb() before counting and
+    // the alpha channel decides whether, or how much, it counts. If every pixel would be skipped
+    // they are all counted instead, so a fully transparent image still has colors.
+    // The colors of the histogram are in ascending order whatever the mode.
+    pub fn new_pixels(pixels: &[[u8; 4]], options: &HistogramOptions) -> Histogram {
+        let alpha = options.alpha;
+        let mut hist = match options.mode {
+            HistogramMode::Exact => {
+                Histogram::merge(count_in_parallel(pixels, options.threads, |chunk| {
+                    Histogram::count_exact(chunk, alpha)
+                }))
+            }
+            HistogramMode::Rgb555 => {
+                let parts = count_in_parallel(pixels, options.threads, |chunk| {
+                    Histogram::count_rgb555(chunk, alpha)
+                });
+                Histogram::from_rgb555(parts)
+            }
+            HistogramMode::Sorted => {
+                Histogram::merge(count_in_parallel(pixels, options.threads, |chunk| {
+                    Histogram::count_sorted(chunk, alpha)
+                }))
+            }
+        };
+        if hist.color_vec.is_empty() && !pixels.is_empty() {
+            let all = HistogramOptions {
+                alpha: AlphaMode::Threshold(0),
+                ..*options
+            };
+            return Histogram::new_pixels(pixels, &all);
+        }
+        hist.pixels = pixels.len();
+        hist
+    }
+
+    fn count_exact(pixels: &[[u8; 4]], alpha: AlphaMode) -> Histogram {
+        let mut counts: HashMap<u32, usize> = HashMap::new();
+        for p in pixels {
+            if let Some(weight) = pixel_weight(p, alpha) {
+                *counts
+                    .entry(pack_rgb(p[RED], p[GREEN], p[BLUE]))
+                    .or_insert(0) += weight;
+            }
+        }
+
+        let mut colors: Vec<(u32, usize)> = counts.into_iter().collect();
+        colors.sort_unstable();
+        let (color_vec, count_vec) = colors.into_iter().unzip();
+        Histogram::new(color_vec, count_vec)
+    }
+
+    // Combines histograms of parts of an image, the colors of each part must be in ascending order
+    fn merge(mut parts: Vec<Histogram>) -> Histogram {
+        if parts.len() == 1 {
+            return parts.remove(0);
+        }
+
+        let mut colors: Vec<(u32, usize)> = parts
+            .iter()
+            .flat_map(|h| h.color_vec.iter().cloned().zip(h.count_vec.iter().cloned()))
+            .collect();
+        colors.sort_unstable();
+
+        let mut color_vec: Vec<u32> = Vec::with_capacity(colors.len());
+        let mut count_vec: Vec<usize> = Vec::with_capacity(colors.len());
+        for (color, count) in colors {
+            if color_vec.last() == Some(&color) {
+                *count_vec.last_mut().unwrap() += count;
+            } else {
+                color_vec.push(color);
+                count_vec.push(count);
+            }
+        }
+        Histogram::new(color_vec, count_vec)
+    }
+
+    // Count, red sum, green sum and blue sum of the pixels in each cell of the reduced color grid
+    fn count_rgb555(pixels: &[[u8; 4]], alpha: AlphaMode) -> Vec<[usize; 4]> {
+        let mut cells = vec![[0usize; 4]; 1 << 15];
+        for p in pixels {
+            if let Some(weight) = pixel_weight(p, alpha) {
+                let index = (p[RED] as usize >> 3) << 10
+                    | (p[GREEN] as usize >> 3) << 5
+                    | (p[BLUE] as usize >> 3);
+                let cell = &mut cells[index];
+                cell[0] += weight;
+                cell[1] += weight * p[RED] as usize;
+                cell[2] += weight * p[GREEN] as usize;
+                cell[3] += weight * p[BLUE] as usize;
+            }
         }
+        cells
     }
 
-    // Build a histogram from a provided u32 array and build a histogram
-    // object from it. Bit-wise operation is used to remove any potential alpha values from the array before adding.
-    pub fn new_pixels(pixels: &[u32]) -> Histogram {
+    // Adds up the cells counted for each part of an image, every cell becomes the average of its colors
+    fn from_rgb555(parts: Vec<Vec<[usize; 4]>>) -> Histogram {
+        let mut parts = parts.into_iter();
+        let mut cells = parts.next().unwrap_or_else(|| vec![[0usize; 4]; 1 << 15]);
+        for part in parts {
+            for (cell, other) in cells.iter_mut().zip(part.iter()) {
+                for (sum, value) in cell.iter_mut().zip(other.iter()) {
+                    *sum += value;
+                }
+            }
+        }
+
+        let mut colors: Vec<(u32, usize)> = cells
+            .iter()
+            .filter(|cell| cell[0] > 0)
+            .map(|cell| {
+                let avg = |sum: usize| ((sum as f64) / (cell[0] as f64)).round() as u8;
+                (pack_rgb(avg(cell[1]), avg(cell[2]), avg(cell[3])), cell[0])
+            })
+            .collect();
+        colors.sort_unstable();
+        let (color_vec, count_vec) = colors.into_iter().unzip();
+        Histogram::new(color_vec, count_vec)
+    }
+
+    fn count_sorted(pixels: &[[u8; 4]], alpha: AlphaMode) -> Histogram {
         let mut color_vec = Vec::new();
         let mut count_vec = Vec::new();
         let mut c_index = 0;
         let mut first_loop = false;
         let mut cur_color = 0;
-        let n = pixels.len();
-        let mut pixels_copy = Vec::with_capacity(n);
+        let mut pixels_copy = Vec::with_capacity(pixels.len());
 
-        for p in pixels.iter().take(n) {
-            pixels_copy.push(0x00FF_FFFF & p);
-        }
-        pixels_copy.sort();
-
-        for p in &pixels_copy {
-            if *p != cur_color || !first_loop {
-                cur_color = *p;
-                c_index += 1;
-                first_loop = true;
+        for p in pixels {
+            if let Some(weight) = pixel_weight(p, alpha) {
+                pixels_copy.push((pack_rgb(p[RED], p[GREEN], p[BLUE]), weight));
             }
         }
-        c_index = 0;
-        cur_color = 0;
-        first_loop = false;
-        for p in &pixels_copy {
+        pixels_copy.sort_unstable();
+
+        for (p, weight) in &pixels_copy {
             if *p != cur_color || !first_loop {
                 cur_color = *p;
                 color_vec.push(cur_color);
-                count_vec.push(1);
+                count_vec.push(*weight);
                 first_loop = true;
                 c_index += 1;
             } else {
-                count_vec[c_index - 1] += 1;
+                count_vec[c_index - 1] += weight;
             }
         }
         Histogram::new(color_vec, count_vec)
     }
+
+    // Number of pixels the histogram was built from. With alpha weighting this differs from the sum of the counts.
+    pub fn pixel_count(&self) -> usize {
+        self.pixels
+    }
+
+    // The distinct colors of the histogram with their pixel counts
+    pub fn channels(&self) -> Vec<ColorChannel> {
+        self.color_vec
+            .iter()
+            .zip(self.count_vec.iter())
+            .map(|(&rgb, &count)| ColorChannel::new_rgb(rgb, count))
+            .collect()
+    }
+
+    // OKLab lightness (0-1) that half of the counted pixels are darker than, to the nearest 0.01
+    pub fn median_lightness(&self) -> f64 {
+        let mut bins = [0usize; 101];
+        for (&rgb, &count) in self.color_vec.iter().zip(self.count_vec.iter()) {
+            let l = ColorChannel::new_rgb(rgb, count).color().to_oklab()[0];
+            bins[(l.clamp(0.0, 1.0) * 100.0).round() as usize] += count;
+        }
+        let half = self.count_vec.iter().sum::<usize>() / 2;
+        let mut seen = 0;
+        for (i, &count) in bins.iter().enumerate() {
+            seen += count;
+            if seen > half {
+                return i as f64 / 100.0;
+            }
+        }
+        0.0
+    }
 }
 
-pub struct MedianCut {
-    image: Vec<ColorChannel>,
-    quantized: Vec<ColorChannel>,
+// Common interface for the color quantization algorithms. Pixels are first counted into a Histogram
+// with Histogram::new_pixels(), then a quantizer reduces the colors in the histogram to at most
+// pallet_size colors, each returned ColorChannel carries the number of pixels it stands for in count.
+// Counts are whatever the histogram counts in, 255ths of a pixel for AlphaMode::Weighted.
+pub trait Quantizer {
+    fn quantize_histogram(&mut self, hist: &Histogram, pallet_size: u32) -> Vec<ColorChannel>;
+
+    // Told the number of pixels in the whole image before any histogram of it is quantized, so a
+    // quantizer that works differently on large images decides once, even when it is then given
+    // the histogram of a downsampled copy
+    fn set_image_pixels(&mut self, _pixels: usize) {}
 }
 
-// Takes a vector of u8 rgb values and converts them to an array of u32's for the purpose of calulation and avoiding overflow
-impl MedianCut {
-    pub fn from_pixel_vec(pixels: &[u8], pallet_size: u32) -> MedianCut {
-        let pixel_len = pixels.len();
-        let mut vec_32_bit = Vec::<u32>::new();
+// Names accepted by quantizer_from_name(), used for the --algorithm option
+pub const ALGORITHMS: [&str; 4] = ["auto", "median-cut", "octree", "wu"];
 
-        let mut dominant_colors = MedianCut {
-            image: Vec::new(),
-            quantized: Vec::new(),
-        };
+// Images with more pixels than this (1080p) are quantized with Wu's algorithm by the auto algorithm
+pub const LARGE_IMAGE_PIXELS: usize = 1920 * 1080;
 
-        // Safe'er' method to get a slice of [u32] out of [u8]
-        for x in (0..pixel_len / 4).step_by(4) {
-            let slice_32 = &pixels[x..(x + 4)];
-            let byte_slice =
-                u32::from_le_bytes(slice_32.try_into().expect("failure converting u8 to u32"));
-            vec_32_bit.push(byte_slice);
-        }
-        // Grab groups of 4 8bit numbers and interpet them as single u32 numbers , slice will be a quarter of the length as a result.
+// Default cap on the number of k-means passes when refining a pallet
+pub const REFINE_ITERATIONS: usize = 10;
+
+// Settings for the quantizers built by quantizer_from_name(), algorithms ignore the ones they have no use for
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QuantizerOptions {
+    // Color space median cut measures, splits and averages its buckets in
+    pub space: ColorSpace,
+    // How median cut picks the bucket to split next
+    pub split: SplitStrategy,
+}
+
+pub fn quantizer_from_name(name: &str, options: &QuantizerOptions) -> Option<Box<dyn Quantizer>> {
+    match name {
+        "median-cut" => Some(Box::new(MedianCut::new(options))),
+        "octree" => Some(Box::new(Octree::default())),
+        "wu" => Some(Box::new(Wu::default())),
+        "auto" => Some(Box::new(Auto::new(options))),
+        _ => None,
+    }
+}
+
+// How median cut picks the next bucket to split
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SplitStrategy {
+    // Breadth first, the bucket that has been split the fewest times. Pallets made before the other
+    // strategies existed used this.
+    Level,
+    // The bucket with the largest population times volume, as in leptonica
+    #[default]
+    Volume,
+    // The bucket with the largest variance weighted by its population
+    Variance,
+}
 
-        dominant_colors.quantized = dominant_colors.median_cut(&vec_32_bit, pallet_size);
-        dominant_colors
-            .quantized
-            .sort_by(|a, b| b.count.cmp(&a.count));
+// Names accepted by SplitStrategy::from_name(), used for the --split option
+pub const SPLIT_STRATEGIES: [&str; 3] = ["level", "volume", "variance"];
 
-        dominant_colors
+impl SplitStrategy {
+    pub fn from_name(name: &str) -> Option<SplitStrategy> {
+        match name {
+            "level" => Some(SplitStrategy::Level),
+            "volume" => Some(SplitStrategy::Volume),
+            "variance" => Some(SplitStrategy::Variance),
+            _ => None,
+        }
     }
+}
+
+#[derive(Default)]
+pub struct MedianCut {
+    image: Vec<ColorPoint>,
+    space: ColorSpace,
+    split: SplitStrategy,
+}
 
-    pub fn get_quantized_colors(&self) -> &Vec<ColorChannel> {
-        &self.quantized
+impl MedianCut {
+    pub fn new(options: &QuantizerOptions) -> MedianCut {
+        MedianCut {
+            image: Vec::new(),
+            space: options.space,
+            split: options.split,
+        }
     }
 
-    fn median_cut(&mut self, pixels: &[u32], pallet_size: u32) -> Vec<ColorChannel> {
-        let color_hist = Histogram::new_pixels(pixels);
+    fn median_cut(&mut self, color_hist: &Histogram, pallet_size: u32) -> Vec<ColorChannel> {
         let mut count = 1;
         let mut done = false;
         let hist_color_total = color_hist.color_vec.len();
 
-        // Move the rgb values with the count to the underlying image vector along with their volume
-        self.image = Vec::with_capacity(hist_color_total);
-        for i in 0..hist_color_total {
-            let rgb = color_hist.color_vec[i];
-            let count = color_hist.count_vec[i];
-            self.image.push(ColorChannel::new_rgb(rgb, count));
-        }
-
         // If their arent enough colors then we just return it early with whatever we have
         if hist_color_total <= pallet_size as usize {
-            self.image.clone()
+            color_hist.channels()
         } else {
+            // Move the colors with the count to the underlying image vector along with their volume,
+            // placed in the color space the buckets are split in
+            let space = self.space;
+            self.image = color_hist
+                .channels()
+                .iter()
+                .map(|c| ColorPoint {
+                    pos: space.to_coords(c.red, c.grn, c.blu),
+                    count: c.count,
+                })
+                .collect();
+
             // Create first box to split from, with all the colors
             let initial_box = ColorBucket::new(0, hist_color_total - 1, 0, &self.image);
             let mut color_set = Vec::new();
@@ -337,29 +692,662 @@ impl MedianCut {
         }
     }
 
-    // Average all the colors in th
-    fn avg_colors(&mut self, color_buckets: &[ColorBucket]) -> Vec<ColorChannel> {
+    // Average all the colors in the buckets
+    fn avg_colors(&self, color_buckets: &[ColorBucket]) -> Vec<ColorChannel> {
         let n = color_buckets.len();
         let mut avg_colors = Vec::with_capacity(n);
         for bucket in color_buckets {
-            avg_colors.push(bucket.avg_color(&mut self.image));
+            avg_colors.push(bucket.avg_color(&self.image, self.space));
         }
         avg_colors
     }
 
-    // Find the next bucket with the smallest level that has more than 2 colors left in it
+    // Find the next bucket that has more than 2 colors left in it to split, depending on the split strategy
+    // the one with the smallest level, the largest population times volume or the largest variance.
     fn get_next_split<'a>(
         &self,
         color_buckets: &'a mut Vec<ColorBucket>,
     ) -> Option<&'a mut ColorBucket> {
+        let step = self.space.step();
+        let priority = |bucket: &ColorBucket| match self.split {
+            SplitStrategy::Level => -bucket.level as f64,
+            SplitStrategy::Volume => bucket.population_volume(step),
+            SplitStrategy::Variance => bucket.sq_error,
+        };
+
         let mut next_split = None;
-        let mut min = std::isize::MAX;
+        let mut max = f64::MIN;
         for bucket in color_buckets {
-            if bucket.color_count() >= 2 && bucket.level < min {
-                min = bucket.level;
+            if bucket.color_count() >= 2 && priority(bucket) > max {
+                max = priority(bucket);
                 next_split = Some(bucket);
             }
         }
         next_split
     }
 }
+
+impl Quantizer for MedianCut {
+    fn quantize_histogram(&mut self, hist: &Histogram, pallet_size: u32) -> Vec<ColorChannel> {
+        let mut quantized = self.median_cut(hist, pallet_size);
+        quantized.sort_by_key(|c| Reverse(c.count));
+        quantized
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct OctreeNode {
+    children: [Option<usize>; 8],
+    leaf: bool,
+    count: usize,
+    r_sum: usize,
+    g_sum: usize,
+    b_sum: usize,
+}
+
+impl OctreeNode {
+    fn avg_color(&self) -> ColorChannel {
+        let n = self.count as f64;
+        ColorChannel::new_colors(
+            (self.r_sum as f64 / n).round() as u8,
+            (self.g_sum as f64 / n).round() as u8,
+            (self.b_sum as f64 / n).round() as u8,
+            self.count,
+        )
+    }
+}
+
+// Octree quantizer. Every histogram color is inserted into a tree eight levels deep where each level
+// picks a child from one bit of the red, green and blue channels, so the leaves start out as the exact colors.
+// Leaves are then folded into their parents, deepest and least populated first, until no more than
+// pallet_size leaves remain and each leaf is the average of the colors under it.
+#[derive(Default)]
+pub struct Octree {
+    nodes: Vec<OctreeNode>,
+    // Nodes with children at each depth, used to pick the next node to fold
+    levels: Vec<Vec<usize>>,
+}
+
+impl Octree {
+    const MAX_DEPTH: usize = 8;
+
+    fn child_index(c: &ColorChannel, depth: usize) -> usize {
+        let shift = 7 - depth;
+        (((c.red >> shift) & 1) << 2 | ((c.grn >> shift) & 1) << 1 | ((c.blu >> shift) & 1))
+            as usize
+    }
+
+    fn insert(&mut self, c: &ColorChannel) {
+        let mut node = 0;
+        for depth in 0..=Octree::MAX_DEPTH {
+            let n = &mut self.nodes[node];
+            n.count += c.count;
+            n.r_sum += c.count * c.red as usize;
+            n.g_sum += c.count * c.grn as usize;
+            n.b_sum += c.count * c.blu as usize;
+
+            if depth == Octree::MAX_DEPTH {
+                n.leaf = true;
+                break;
+            }
+
+            let index = Octree::child_index(c, depth);
+            node = match self.nodes[node].children[index] {
+                Some(child) => child,
+                None => {
+                    if self.nodes[node].children.iter().all(|x| x.is_none()) {
+                        self.levels[depth].push(node);
+                    }
+                    let child = self.nodes.len();
+                    self.nodes.push(OctreeNode::default());
+                    self.nodes[node].children[index] = Some(child);
+                    child
+                }
+            };
+        }
+    }
+
+    // Folds the children of a node into it and returns how many leaves were removed. When folding
+    // every child would remove more than excess leaves, only the excess + 1 least populated children
+    // are merged together so the tree lands exactly on the pallet size.
+    fn reduce(&mut self, node: usize, excess: usize) -> usize {
+        let mut children: Vec<usize> = self.nodes[node]
+            .children
+            .iter()
+            .flatten()
+            .cloned()
+            .collect();
+        if children.len() - 1 <= excess {
+            self.nodes[node].children = [None; 8];
+            self.nodes[node].leaf = true;
+            return children.len() - 1;
+        }
+
+        children.sort_by_key(|&c| self.nodes[c].count);
+        let keep = children[0];
+        for &child in &children[1..=excess] {
+            let merged = self.nodes[child];
+            let k = &mut self.nodes[keep];
+            k.count += merged.count;
+            k.r_sum += merged.r_sum;
+            k.g_sum += merged.g_sum;
+            k.b_sum += merged.b_sum;
+            for slot in self.nodes[node].children.iter_mut() {
+                if *slot == Some(child) {
+                    *slot = None;
+                }
+            }
+        }
+        excess
+    }
+
+    fn collect_leaves(&self, node: usize, leaves: &mut Vec<ColorChannel>) {
+        let n = &self.nodes[node];
+        if n.leaf {
+            leaves.push(n.avg_color());
+            return;
+        }
+        for child in n.children.iter().flatten() {
+            self.collect_leaves(*child, leaves);
+        }
+    }
+}
+
+impl Quantizer for Octree {
+    fn quantize_histogram(&mut self, hist: &Histogram, pallet_size: u32) -> Vec<ColorChannel> {
+        let colors = hist.channels();
+        if colors.is_empty() {
+            return Vec::new();
+        }
+
+        self.nodes = vec![OctreeNode::default()];
+        self.levels = vec![Vec::new(); Octree::MAX_DEPTH];
+        for c in &colors {
+            self.insert(c);
+        }
+
+        // Counts of the inner nodes never change while folding, so each level can be ordered once
+        for level in self.levels.iter_mut() {
+            let nodes = &self.nodes;
+            level.sort_by_key(|&n| Reverse(nodes[n].count));
+        }
+
+        let target = pallet_size.max(1) as usize;
+        let mut leaf_count = colors.len();
+        let mut depth = Octree::MAX_DEPTH;
+        while leaf_count > target && depth > 0 {
+            match self.levels[depth - 1].pop() {
+                Some(node) => leaf_count -= self.reduce(node, leaf_count - target),
+                None => depth -= 1,
+            }
+        }
+
+        let mut leaves = Vec::with_capacity(leaf_count);
+        self.collect_leaves(0, &mut leaves);
+        leaves.sort_by_key(|c| Reverse(c.count));
+        leaves
+    }
+}
+
+// Number of cells per channel in Wu's moment tables, 5 bits per channel plus a zero row for the cumulative sums
+const WU_SIDE: usize = 33;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct WuBox {
+    r0: usize,
+    r1: usize,
+    g0: usize,
+    g1: usize,
+    b0: usize,
+    b1: usize,
+    vol: usize,
+}
+
+// Xiaolin Wu's variance minimizing quantizer, from Graphics Gems II.
+// The histogram is reduced to a 32x32x32 grid and turned into cumulative moments, after which the sum of
+// any box of colors can be read in constant time. The box with the largest variance is repeatedly cut in two
+// where the sum of squared error of the halves is smallest, until pallet_size boxes have been made.
+#[derive(Default)]
+pub struct Wu {
+    wt: Vec<i64>,
+    mr: Vec<i64>,
+    mg: Vec<i64>,
+    mb: Vec<i64>,
+    m2: Vec<f64>,
+}
+
+impl Wu {
+    fn index(r: usize, g: usize, b: usize) -> usize {
+        r * WU_SIDE * WU_SIDE + g * WU_SIDE + b
+    }
+
+    // Fill the moment tables from the histogram and make them cumulative
+    fn build_moments(&mut self, colors: &[ColorChannel]) {
+        let size = WU_SIDE * WU_SIDE * WU_SIDE;
+        self.wt = vec![0; size];
+        self.mr = vec![0; size];
+        self.mg = vec![0; size];
+        self.mb = vec![0; size];
+        self.m2 = vec![0.0; size];
+
+        for c in colors {
+            let i = Wu::index(
+                (c.red >> 3) as usize + 1,
+                (c.grn >> 3) as usize + 1,
+                (c.blu >> 3) as usize + 1,
+            );
+            let n = c.count as i64;
+            let (r, g, b) = (c.red as i64, c.grn as i64, c.blu as i64);
+            self.wt[i] += n;
+            self.mr[i] += n * r;
+            self.mg[i] += n * g;
+            self.mb[i] += n * b;
+            self.m2[i] += (n * (r * r + g * g + b * b)) as f64;
+        }
+
+        for r in 1..WU_SIDE {
+            let mut area = [0i64; WU_SIDE];
+            let mut area_r = [0i64; WU_SIDE];
+            let mut area_g = [0i64; WU_SIDE];
+            let mut area_b = [0i64; WU_SIDE];
+            let mut area2 = [0f64; WU_SIDE];
+            for g in 1..WU_SIDE {
+                let mut line = 0;
+                let mut line_r = 0;
+                let mut line_g = 0;
+                let mut line_b = 0;
+                let mut line2 = 0.0;
+                for b in 1..WU_SIDE {
+                    let i = Wu::index(r, g, b);
+                    let prev = Wu::index(r - 1, g, b);
+                    line += self.wt[i];
+                    line_r += self.mr[i];
+                    line_g += self.mg[i];
+                    line_b += self.mb[i];
+                    line2 += self.m2[i];
+                    area[b] += line;
+                    area_r[b] += line_r;
+                    area_g[b] += line_g;
+                    area_b[b] += line_b;
+                    area2[b] += line2;
+                    self.wt[i] = self.wt[prev] + area[b];
+                    self.mr[i] = self.mr[prev] + area_r[b];
+                    self.mg[i] = self.mg[prev] + area_g[b];
+                    self.mb[i] = self.mb[prev] + area_b[b];
+                    self.m2[i] = self.m2[prev] + area2[b];
+                }
+            }
+        }
+    }
+
+    // Sum of a moment table over a box, by inclusion-exclusion on the cumulative corners
+    fn volume(cube: &WuBox, m: &[i64]) -> i64 {
+        m[Wu::index(cube.r1, cube.g1, cube.b1)]
+            - m[Wu::index(cube.r1, cube.g1, cube.b0)]
+            - m[Wu::index(cube.r1, cube.g0, cube.b1)]
+            + m[Wu::index(cube.r1, cube.g0, cube.b0)]
+            - m[Wu::index(cube.r0, cube.g1, cube.b1)]
+            + m[Wu::index(cube.r0, cube.g1, cube.b0)]
+            + m[Wu::index(cube.r0, cube.g0, cube.b1)]
+            - m[Wu::index(cube.r0, cube.g0, cube.b0)]
+    }
+
+    fn volume_f(cube: &WuBox, m: &[f64]) -> f64 {
+        m[Wu::index(cube.r1, cube.g1, cube.b1)]
+            - m[Wu::index(cube.r1, cube.g1, cube.b0)]
+            - m[Wu::index(cube.r1, cube.g0, cube.b1)]
+            + m[Wu::index(cube.r1, cube.g0, cube.b0)]
+            - m[Wu::index(cube.r0, cube.g1, cube.b1)]
+            + m[Wu::index(cube.r0, cube.g1, cube.b0)]
+            + m[Wu::index(cube.r0, cube.g0, cube.b1)]
+            - m[Wu::index(cube.r0, cube.g0, cube.b0)]
+    }
+
+    // Part of the box sum that does not depend on where the box is cut along c_type
+    fn bottom(cube: &WuBox, c_type: Color, m: &[i64]) -> i64 {
+        match c_type {
+            Color::Red => {
+                -m[Wu::index(cube.r0, cube.g1, cube.b1)]
+                    + m[Wu::index(cube.r0, cube.g1, cube.b0)]
+                    + m[Wu::index(cube.r0, cube.g0, cube.b1)]
+                    - m[Wu::index(cube.r0, cube.g0, cube.b0)]
+            }
+            Color::Green => {
+                -m[Wu::index(cube.r1, cube.g0, cube.b1)]
+                    + m[Wu::index(cube.r1, cube.g0, cube.b0)]
+                    + m[Wu::index(cube.r0, cube.g0, cube.b1)]
+                    - m[Wu::index(cube.r0, cube.g0, cube.b0)]
+            }
+            Color::Blue => {
+                -m[Wu::index(cube.r1, cube.g1, cube.b0)]
+                    + m[Wu::index(cube.r1, cube.g0, cube.b0)]
+                    + m[Wu::index(cube.r0, cube.g1, cube.b0)]
+                    - m[Wu::index(cube.r0, cube.g0, cube.b0)]
+            }
+        }
+    }
+
+    // Part of the box sum that depends on the cut position pos along c_type
+    fn top(cube: &WuBox, c_type: Color, pos: usize, m: &[i64]) -> i64 {
+        match c_type {
+            Color::Red => {
+                m[Wu::index(pos, cube.g1, cube.b1)]
+                    - m[Wu::index(pos, cube.g1, cube.b0)]
+                    - m[Wu::index(pos, cube.g0, cube.b1)]
+                    + m[Wu::index(pos, cube.g0, cube.b0)]
+            }
+            Color::Green => {
+                m[Wu::index(cube.r1, pos, cube.b1)]
+                    - m[Wu::index(cube.r1, pos, cube.b0)]
+                    - m[Wu::index(cube.r0, pos, cube.b1)]
+                    + m[Wu::index(cube.r0, pos, cube.b0)]
+            }
+            Color::Blue => {
+                m[Wu::index(cube.r1, cube.g1, pos)]
+                    - m[Wu::index(cube.r1, cube.g0, pos)]
+                    - m[Wu::index(cube.r0, cube.g1, pos)]
+                    + m[Wu::index(cube.r0, cube.g0, pos)]
+            }
+        }
+    }
+
+    // Weighted variance of the colors in a box
+    fn variance(&self, cube: &WuBox) -> f64 {
+        let dr = Wu::volume(cube, &self.mr) as f64;
+        let dg = Wu::volume(cube, &self.mg) as f64;
+        let db = Wu::volume(cube, &self.mb) as f64;
+        let xx = Wu::volume_f(cube, &self.m2);
+        let w = Wu::volume(cube, &self.wt) as f64;
+        if w == 0.0 {
+            0.0
+        } else {
+            xx - (dr * dr + dg * dg + db * db) / w
+        }
+    }
+
+    // Find the cut along c_type that maximizes the between-box variance of the two halves.
+    // Returns the score and position of the best cut, or None if every cut leaves a half empty.
+    fn maximize(
+        &self,
+        cube: &WuBox,
+        c_type: Color,
+        first: usize,
+        last: usize,
+    ) -> Option<(f64, usize)> {
+        let whole_r = Wu::volume(cube, &self.mr);
+        let whole_g = Wu::volume(cube, &self.mg);
+        let whole_b = Wu::volume(cube, &self.mb);
+        let whole_w = Wu::volume(cube, &self.wt);
+
+        let base_r = Wu::bottom(cube, c_type, &self.mr);
+        let base_g = Wu::bottom(cube, c_type, &self.mg);
+        let base_b = Wu::bottom(cube, c_type, &self.mb);
+        let base_w = Wu::bottom(cube, c_type, &self.wt);
+
+        let mut best: Option<(f64, usize)> = None;
+        for pos in first..last {
+            let half_r = base_r + Wu::top(cube, c_type, pos, &self.mr);
+            let half_g = base_g + Wu::top(cube, c_type, pos, &self.mg);
+            let half_b = base_b + Wu::top(cube, c_type, pos, &self.mb);
+            let half_w = base_w + Wu::top(cube, c_type, pos, &self.wt);
+            if half_w == 0 || half_w == whole_w {
+                continue;
+            }
+
+            // Squared moment sums overflow i64 on large bright images, so they are squared as f64
+            let (hr, hg, hb) = (half_r as f64, half_g as f64, half_b as f64);
+            let lower = (hr * hr + hg * hg + hb * hb) / half_w as f64;
+            let (or, og, ob) = (
+                (whole_r - half_r) as f64,
+                (whole_g - half_g) as f64,
+                (whole_b - half_b) as f64,
+            );
+            let upper = (or * or + og * og + ob * ob) / (whole_w - half_w) as f64;
+            let score = lower + upper;
+            if best.is_none_or(|(max, _)| score > max) {
+                best = Some((score, pos));
+            }
+        }
+        best
+    }
+
+    // Splits cube along the dimension with the best cut, the upper half is returned as a new box
+    fn cut(&self, cube: &mut WuBox) -> Option<WuBox> {
+        let red = self.maximize(cube, Color::Red, cube.r0 + 1, cube.r1);
+        let green = self.maximize(cube, Color::Green, cube.g0 + 1, cube.g1);
+        let blue = self.maximize(cube, Color::Blue, cube.b0 + 1, cube.b1);
+
+        let score = |cut: Option<(f64, usize)>| cut.map_or(f64::MIN, |(max, _)| max);
+        let (c_type, pos) = if score(red) >= score(green) && score(red) >= score(blue) {
+            (Color::Red, red?.1)
+        } else if score(green) >= score(blue) {
+            (Color::Green, green?.1)
+        } else {
+            (Color::Blue, blue?.1)
+        };
+
+        let mut upper = *cube;
+        match c_type {
+            Color::Red => {
+                upper.r0 = pos;
+                cube.r1 = pos;
+            }
+            Color::Green => {
+                upper.g0 = pos;
+                cube.g1 = pos;
+            }
+            Color::Blue => {
+                upper.b0 = pos;
+                cube.b1 = pos;
+            }
+        }
+        cube.vol = (cube.r1 - cube.r0) * (cube.g1 - cube.g0) * (cube.b1 - cube.b0);
+        upper.vol = (upper.r1 - upper.r0) * (upper.g1 - upper.g0) * (upper.b1 - upper.b0);
+        Some(upper)
+    }
+
+    fn avg_color(&self, cube: &WuBox) -> ColorChannel {
+        let w = Wu::volume(cube, &self.wt);
+        let avg = |m: &[i64]| (Wu::volume(cube, m) as f64 / w as f64).round() as u8;
+        ColorChannel::new_colors(avg(&self.mr), avg(&self.mg), avg(&self.mb), w as usize)
+    }
+}
+
+impl Quantizer for Wu {
+    fn quantize_histogram(&mut self, hist: &Histogram, pallet_size: u32) -> Vec<ColorChannel> {
+        let colors = hist.channels();
+        // Nothing to reduce, the colors are already a pallet
+        if colors.len() <= pallet_size as usize {
+            let mut pallet = colors;
+            pallet.sort_by_key(|c| Reverse(c.count));
+            return pallet;
+        }
+
+        self.build_moments(&colors);
+        let last = WU_SIDE - 1;
+        let mut cubes = vec![WuBox {
+            r1: last,
+            g1: last,
+            b1: last,
+            vol: last * last * last,
+            ..Default::default()
+        }];
+        let mut variances = vec![0.0];
+        let mut next = 0;
+
+        while cubes.len() < pallet_size as usize {
+            match self.cut(&mut cubes[next]) {
+                Some(upper) => {
+                    variances[next] = if cubes[next].vol > 1 {
+                        self.variance(&cubes[next])
+                    } else {
+                        0.0
+                    };
+                    variances.push(if upper.vol > 1 {
+                        self.variance(&upper)
+                    } else {
+                        0.0
+                    });
+                    cubes.push(upper);
+                }
+                // The box could not be cut, don't try it again
+                None => variances[next] = 0.0,
+            }
+
+            next = 0;
+            for (i, v) in variances.iter().enumerate() {
+                if *v > variances[next] {
+                    next = i;
+                }
+            }
+            if variances[next] <= 0.0 {
+                break;
+            }
+        }
+
+        let mut pallet: Vec<ColorChannel> = cubes
+            .iter()
+            .filter(|c| Wu::volume(c, &self.wt) > 0)
+            .map(|c| self.avg_color(c))
+            .collect();
+        pallet.sort_by_key(|c| Reverse(c.count));
+        pallet
+    }
+}
+
+// Default algorithm, uses Wu's quantizer on large wallpapers where it is both faster and better than
+// median cut, and median cut on everything else so smaller images keep their familiar pallets.
+pub struct Auto {
+    options: QuantizerOptions,
+    // Pixels in the whole image, the histogram's pixel count is used until set
+    image_pixels: Option<usize>,
+}
+
+impl Auto {
+    pub fn new(options: &QuantizerOptions) -> Auto {
+        Auto {
+            options: *options,
+            image_pixels: None,
+        }
+    }
+}
+
+impl Quantizer for Auto {
+    fn set_image_pixels(&mut self, pixels: usize) {
+        self.image_pixels = Some(pixels);
+    }
+
+    fn quantize_histogram(&mut self, hist: &Histogram, pallet_size: u32) -> Vec<ColorChannel> {
+        if self.image_pixels.unwrap_or_else(|| hist.pixel_count()) > LARGE_IMAGE_PIXELS {
+            Wu::default().quantize_histogram(hist, pallet_size)
+        } else {
+            MedianCut::new(&self.options).quantize_histogram(hist, pallet_size)
+        }
+    }
+}
+
+// Wraps another quantizer and refines its pallet with Lloyd's k-means algorithm. The pallet of the inner
+// quantizer seeds the centroids, so the result keeps the same number of colors but each one moves to the
+// weighted mean of the histogram colors closest to it instead of staying a bucket average.
+pub struct KMeansRefine {
+    inner: Box<dyn Quantizer>,
+    max_iterations: usize,
+}
+
+impl KMeansRefine {
+    pub fn new(inner: Box<dyn Quantizer>, max_iterations: usize) -> KMeansRefine {
+        KMeansRefine {
+            inner,
+            max_iterations,
+        }
+    }
+}
+
+impl Quantizer for KMeansRefine {
+    fn quantize_histogram(&mut self, hist: &Histogram, pallet_size: u32) -> Vec<ColorChannel> {
+        let seeds = self.inner.quantize_histogram(hist, pallet_size);
+        let mut refined = kmeans(&hist.channels(), &seeds, self.max_iterations);
+        refined.sort_by_key(|c| Reverse(c.count));
+        refined
+    }
+
+    fn set_image_pixels(&mut self, pixels: usize) {
+        self.inner.set_image_pixels(pixels);
+    }
+}
+
+fn distance_sq(a: &[f64; 3], c: &ColorChannel) -> f64 {
+    let dr = a[0] - c.red as f64;
+    let dg = a[1] - c.grn as f64;
+    let db = a[2] - c.blu as f64;
+    dr * dr + dg * dg + db * db
+}
+
+// Runs k-means over the histogram colors starting from the seed colors, until no centroid moves
+// by more than a rounding step or max_iterations passes have been made.
+// A centroid that loses all of its colors keeps its last position with a count of zero.
+fn kmeans(
+    colors: &[ColorChannel],
+    seeds: &[ColorChannel],
+    max_iterations: usize,
+) -> Vec<ColorChannel> {
+    let mut centroids: Vec<[f64; 3]> = seeds
+        .iter()
+        .map(|c| [c.red as f64, c.grn as f64, c.blu as f64])
+        .collect();
+    let mut counts = vec![0; centroids.len()];
+    if centroids.is_empty() || max_iterations == 0 {
+        return seeds.to_vec();
+    }
+
+    for _ in 0..max_iterations {
+        let mut sums = vec![[0.0; 3]; centroids.len()];
+        counts = vec![0; centroids.len()];
+
+        for c in colors {
+            let mut nearest = 0;
+            let mut best = f64::MAX;
+            for (i, centroid) in centroids.iter().enumerate() {
+                let d = distance_sq(centroid, c);
+                if d < best {
+                    best = d;
+                    nearest = i;
+                }
+            }
+            let w = c.count as f64;
+            sums[nearest][0] += w * c.red as f64;
+            sums[nearest][1] += w * c.grn as f64;
+            sums[nearest][2] += w * c.blu as f64;
+            counts[nearest] += c.count;
+        }
+
+        let mut moved = false;
+        for (i, centroid) in centroids.iter_mut().enumerate() {
+            if counts[i] == 0 {
+                continue;
+            }
+            let n = counts[i] as f64;
+            let next = [sums[i][0] / n, sums[i][1] / n, sums[i][2] / n];
+            if (0..3).any(|k| (next[k] - centroid[k]).abs() >= 0.5) {
+                moved = true;
+            }
+            *centroid = next;
+        }
+        if !moved {
+            break;
+        }
+    }
+
+    centroids
+        .iter()
+        .zip(counts)
+        .map(|(c, count)| {
+            ColorChannel::new_colors(
+                c[0].round() as u8,
+                c[1].round() as u8,
+                c[2].round() as u8,
+                count,
+            )
+        })
+        .collect()
+}
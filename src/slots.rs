@@ -0,0 +1,165 @@
+// Which *colorN slot each pallet color is written to. Terminal programs expect color1 to be red,
+// color2 green and so on, so the ANSI strategy matches the colors to those roles by hue and
+// lightness, with the Hungarian algorithm to find the matching with the lowest total cost.
+use crate::q_image::ColorChannel;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Slots {
+    // Most common color first, the order the quantizer returns them in
+    #[default]
+    Population,
+    // black, red, green, yellow, blue, magenta, cyan, white, then their bright versions
+    Ansi,
+}
+
+// Names accepted by Slots::from_name(), used for the --slots option
+pub const SLOT_STRATEGIES: [&str; 2] = ["population", "ansi"];
+
+impl Slots {
+    pub fn from_name(name: &str) -> Option<Slots> {
+        match name {
+            "population" => Some(Slots::Population),
+            "ansi" => Some(Slots::Ansi),
+            _ => None,
+        }
+    }
+}
+
+// What an ANSI slot wants, either a grey of some lightness or a hue (OKLCH degrees)
+#[derive(Debug, Clone, Copy)]
+enum Role {
+    Grey(f64),
+    Hue(f64),
+}
+
+// OKLCH hues of the sRGB primaries and secondaries
+const RED: f64 = 29.2;
+const YELLOW: f64 = 109.8;
+const GREEN: f64 = 142.5;
+const CYAN: f64 = 194.8;
+const BLUE: f64 = 264.1;
+const MAGENTA: f64 = 328.4;
+
+const ANSI_ROLES: [Role; 16] = [
+    Role::Grey(0.0),
+    Role::Hue(RED),
+    Role::Hue(GREEN),
+    Role::Hue(YELLOW),
+    Role::Hue(BLUE),
+    Role::Hue(MAGENTA),
+    Role::Hue(CYAN),
+    Role::Grey(0.8),
+    Role::Grey(0.45),
+    Role::Hue(RED),
+    Role::Hue(GREEN),
+    Role::Hue(YELLOW),
+    Role::Hue(BLUE),
+    Role::Hue(MAGENTA),
+    Role::Hue(CYAN),
+    Role::Grey(1.0),
+];
+
+// Chroma above which a color's hue is trusted completely
+const FULL_CHROMA: f64 = 0.1;
+
+// Cost of writing a color with this OKLCH lightness, chroma and hue to the slot, roughly 0 to 2.
+// Grey slots weigh lightness heavily so black stays the darkest color even in images with one hue.
+fn cost(slot: usize, lch: [f64; 3]) -> f64 {
+    let [l, chroma, hue] = lch;
+    let bright = slot >= 8;
+    match ANSI_ROLES[slot] {
+        Role::Grey(target) => 2.0 * ((l - target).abs() + chroma),
+        Role::Hue(target) => {
+            let diff = (hue - target).rem_euclid(360.0);
+            let diff = diff.min(360.0 - diff) / 180.0;
+            // A grey's hue is noise, it is an equally poor fit for every colored slot
+            let trust = (chroma / FULL_CHROMA).min(1.0);
+            let lightness = if bright { 1.0 - l } else { l };
+            trust * diff + (1.0 - trust) * 0.9 + 0.25 * lightness
+        }
+    }
+}
+
+// Reorders the pallet so the first colors (up to 16) fill the ANSI roles as well as they can. With
+// fewer than 16 colors only the first roles are used, colors past the 16th keep their order.
+pub fn assign_ansi(pallet: &[ColorChannel]) -> Vec<ColorChannel> {
+    let n = pallet.len().min(ANSI_ROLES.len());
+    let costs: Vec<Vec<f64>> = (0..n)
+        .map(|slot| {
+            pallet[..n]
+                .iter()
+                .map(|c| cost(slot, c.color().to_oklch()))
+                .collect()
+        })
+        .collect();
+
+    let mut assigned: Vec<ColorChannel> = hungarian(&costs).iter().map(|&c| pallet[c]).collect();
+    assigned.extend_from_slice(&pallet[n..]);
+    assigned
+}
+
+// Minimum cost assignment for a square cost matrix, returns the column picked for each row.
+// The O(n^3) version with row and column potentials, see
+// https://en.wikipedia.org/wiki/Hungarian_algorithm
+pub fn hungarian(costs: &[Vec<f64>]) -> Vec<usize> {
+    let n = costs.len();
+    // Index 0 is a dummy row and column, rows and columns are numbered from 1
+    let mut row_potential = vec![0.0; n + 1];
+    let mut col_potential = vec![0.0; n + 1];
+    // Row matched to each column
+    let mut matched = vec![0; n + 1];
+    let mut way = vec![0; n + 1];
+
+    for row in 1..=n {
+        matched[0] = row;
+        let mut col = 0;
+        let mut min_slack = vec![f64::MAX; n + 1];
+        let mut used = vec![false; n + 1];
+        loop {
+            used[col] = true;
+            let r = matched[col];
+            let mut delta = f64::MAX;
+            let mut next = 0;
+            for j in 1..=n {
+                if used[j] {
+                    continue;
+                }
+                let slack = costs[r - 1][j - 1] - row_potential[r] - col_potential[j];
+                if slack < min_slack[j] {
+                    min_slack[j] = slack;
+                    way[j] = col;
+                }
+                if min_slack[j] < delta {
+                    delta = min_slack[j];
+                    next = j;
+                }
+            }
+            for j in 0..=n {
+                if used[j] {
+                    row_potential[matched[j]] += delta;
+                    col_potential[j] -= delta;
+                } else {
+                    min_slack[j] -= delta;
+                }
+            }
+            col = next;
+            if matched[col] == 0 {
+                break;
+            }
+        }
+        // Flip the augmenting path
+        while col != 0 {
+            let prev = way[col];
+            matched[col] = matched[prev];
+            col = prev;
+        }
+    }
+
+    let mut assignment = vec![0; n];
+    for col in 1..=n {
+        if matched[col] != 0 {
+            assignment[matched[col] - 1] = col - 1;
+        }
+    }
+    assignment
+}
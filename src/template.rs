@@ -0,0 +1,162 @@
+// Templates, config files for other programs with placeholders where the colors of a theme go.
+// Every file in a templates directory is rendered into the output directory under the same name,
+// so any program can be themed without code changes.
+//
+// A placeholder is a color name in braces, {color1}, {background}, {foreground} or {cursor}, written
+// as #RRGGBB. It can be followed by adjustments from adjust.rs, {color4.lighten(10)}, and ends with
+// an optional format:
+//
+//     .hex    #RRGGBB, the default
+//     .strip  RRGGBB
+//     .rgb    r,g,b from 0 to 255
+//     .hsl    h,s%,l% with the hue in degrees, for hsl() in CSS
+//     .hsv    h,s%,v%
+//     .red    .green or .blue, one channel from 0 to 255
+//
+// {{ and }} are literal braces. Braces around anything that isn't a color name are left as they are,
+// so CSS and JSON templates mostly don't need them.
+use crate::adjust::{adjust_pallet, Adjustment};
+use crate::q_image::ColorChannel;
+use std::fs::{create_dir_all, read_dir, read_to_string, write};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+// Renders a template with the named colors. Errors name the line of the placeholder that is wrong.
+pub fn render(text: &str, colors: &[(String, ColorChannel)]) -> Result<String, String> {
+    let mut rendered = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(['{', '}']) {
+        rendered.push_str(&rest[..start]);
+        let tail = &rest[start..];
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            rendered.push_str(&tail[..1]);
+            rest = &tail[2..];
+            continue;
+        }
+        let placeholder = Some(tail)
+            .filter(|t| t.starts_with('{'))
+            .and_then(|t| t[1..].find(|c| c == '}' || c == '{' || char::is_whitespace(c)))
+            .filter(|&end| tail.as_bytes()[end + 1] == b'}')
+            .map(|end| &tail[1..=end]);
+        let color = placeholder.and_then(|p| {
+            let name = p.split('.').next().unwrap();
+            colors.iter().find(|(n, _)| n == name).map(|&(_, c)| c)
+        });
+        match (placeholder, color) {
+            (Some(placeholder), Some(color)) => {
+                let line = text[..text.len() - rest.len() + start]
+                    .matches('\n')
+                    .count()
+                    + 1;
+                let value = fill(placeholder, color)
+                    .map_err(|e| format!("line {}: {{{}}}: {}", line, placeholder, e))?;
+                rendered.push_str(&value);
+                rest = &tail[placeholder.len() + 2..];
+            }
+            _ => {
+                rendered.push_str(&tail[..1]);
+                rest = &tail[1..];
+            }
+        }
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+// The value of one placeholder, without its braces, for the color it names
+fn fill(placeholder: &str, color: ColorChannel) -> Result<String, String> {
+    let mut color = color;
+    let mut rest = &placeholder[placeholder.find('.').unwrap_or(placeholder.len())..];
+    while let Some(modifier) = rest.strip_prefix('.') {
+        // Amounts can have a decimal point, so an adjustment ends at its closing parenthesis
+        let end = match (modifier.find('('), modifier.find('.')) {
+            (Some(open), Some(dot)) if open < dot => modifier
+                .find(')')
+                .map(|close| close + 1)
+                .ok_or("missing )")?,
+            (_, Some(dot)) => dot,
+            (_, None) => modifier.len(),
+        };
+        let (step, after) = modifier.split_at(end);
+        rest = after;
+
+        let c = color;
+        let format = match step {
+            "hex" => format!("#{:02X}{:02X}{:02X}", c.red, c.grn, c.blu),
+            "strip" => format!("{:02X}{:02X}{:02X}", c.red, c.grn, c.blu),
+            "rgb" => format!("{},{},{}", c.red, c.grn, c.blu),
+            "hsl" | "hsv" => {
+                let [h, s, l_or_v] = if step == "hsl" {
+                    c.color().to_hsl()
+                } else {
+                    c.color().to_hsv()
+                };
+                format!(
+                    "{},{}%,{}%",
+                    h.round(),
+                    (s * 100.0).round(),
+                    (l_or_v * 100.0).round()
+                )
+            }
+            "red" => c.red.to_string(),
+            "green" => c.grn.to_string(),
+            "blue" => c.blu.to_string(),
+            _ => {
+                let mut adjusted = [c];
+                adjust_pallet(&mut adjusted, &[adjustment(step)?]);
+                color = adjusted[0];
+                continue;
+            }
+        };
+        if !rest.is_empty() {
+            return Err(format!("nothing can follow .{}", step));
+        }
+        return Ok(format);
+    }
+    Ok(format!(
+        "#{:02X}{:02X}{:02X}",
+        color.red, color.grn, color.blu
+    ))
+}
+
+// An adjustment written like lighten(10)
+fn adjustment(step: &str) -> Result<Adjustment, String> {
+    let unknown = || format!("unknown format or adjustment .{}", step);
+    let (name, amount) = step
+        .strip_suffix(')')
+        .and_then(|s| s.split_once('('))
+        .ok_or_else(unknown)?;
+    let amount = amount
+        .trim()
+        .parse()
+        .map_err(|_| format!("expected a number in .{}", step))?;
+    Adjustment::from_name(name, amount).ok_or_else(unknown)
+}
+
+// Renders every file in the templates directory into the output directory, which is created if
+// needed. Returns the files written.
+pub fn render_dir(
+    templates: &Path,
+    output: &Path,
+    colors: &[(String, ColorChannel)],
+) -> Result<Vec<PathBuf>, Error> {
+    create_dir_all(output)?;
+    let mut entries: Vec<PathBuf> = read_dir(templates)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<_, _>>()?;
+    entries.sort();
+
+    let mut written = Vec::new();
+    for template in entries.into_iter().filter(|p| p.is_file()) {
+        let rendered = render(&read_to_string(&template)?, colors).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{}: {}", template.display(), e),
+            )
+        })?;
+        let path = output.join(template.file_name().unwrap());
+        write(&path, rendered)?;
+        written.push(path);
+    }
+    Ok(written)
+}
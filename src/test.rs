@@ -1,17 +1,45 @@
 #[cfg(test)]
 mod tests {
+    use crate::adjust::{adjust_pallet, parse_adjustments, Adjustment};
+    use crate::adjustment_order;
+    use crate::color::{Color, ColorSpace, Luminance, COLOR_SPACES, LUMINANCE_MODELS};
     use crate::colors_from_image;
+    use crate::contrast::{apca_lc, enforce, wcag_ratio, Contrast, Mode};
+    use crate::cvd::{adapt, collisions, Deficiency, DEFAULT_CVD_THRESHOLD, DEFICIENCIES};
+    use crate::delta_e::{ciede2000, pairwise, DeltaE};
     use crate::list_loaded_colors;
+    use crate::palette::{
+        complete_pallet, derive_bright, distinct_pallet, remove_duplicates, Completion, Scheme,
+        DEFAULT_BRIGHT_CHROMA, DEFAULT_BRIGHT_LIGHTNESS, DEFAULT_DEDUPE_THRESHOLD,
+    };
+    use crate::preprocess::{pallet_drift, Downsample, Target};
+    use crate::q_image::{
+        pack_rgb, quantizer_from_name, rgba_pixels, AlphaMode, ColorChannel, Histogram,
+        HistogramMode, HistogramOptions, KMeansRefine, MedianCut, Octree, Quantizer,
+        QuantizerOptions, SplitStrategy, Wu, ALGORITHMS, LARGE_IMAGE_PIXELS,
+    };
+    use crate::read_colors;
+    use crate::slots::{assign_ansi, hungarian};
+    use crate::template::render;
+    use crate::theme::{read_slots, to_hex, Theme};
+    use crate::ThemeOptions;
+    use image::{Rgba, RgbaImage};
     use std::env;
-    use std::path::Path;
     use std::process::Command;
+    use std::time::Instant;
     #[test]
     fn loaded_to_xsystem() {
         println!("Testing that rusty-theme loads colorscheme and saves it to Xsystem database, verify that colors for generated output and xrdb colors match up\n");
 
         let image_file_name = "res/snow_sunset.jpeg";
         let output_file = "test_colorscheme_loaded_to_xsystem";
-        colors_from_image(image_file_name, output_file, false);
+        colors_from_image(
+            image_file_name,
+            output_file,
+            &mut MedianCut::default(),
+            &ThemeOptions::default(),
+        )
+        .unwrap();
         let p_output = Command::new("xrdb")
             .arg(output_file)
             .status()
@@ -34,7 +62,17 @@ mod tests {
 
         let image_file_name = "res/snow_sunset.jpeg";
         let output_file = "test_colorscheme_random_to_xsystem";
-        colors_from_image(image_file_name, output_file, true);
+        let options = ThemeOptions {
+            random: true,
+            ..Default::default()
+        };
+        colors_from_image(
+            image_file_name,
+            output_file,
+            &mut MedianCut::default(),
+            &options,
+        )
+        .unwrap();
         let p_output = Command::new("xrdb")
             .arg(output_file)
             .status()
@@ -57,9 +95,1087 @@ mod tests {
 
         let image_file_name = "res/snow_sunset.jpeg";
         let output_file = "test_name";
-        colors_from_image(image_file_name, output_file, false);
+        colors_from_image(
+            image_file_name,
+            output_file,
+            &mut MedianCut::default(),
+            &ThemeOptions::default(),
+        )
+        .unwrap();
         let mut path = env::current_dir().unwrap();
         path.push("test_name");
         assert!(path.exists());
     }
+    #[test]
+    fn every_algorithm_has_a_quantizer() {
+        for name in ALGORITHMS.iter() {
+            assert!(
+                quantizer_from_name(name, &QuantizerOptions::default()).is_some(),
+                "{} not constructed",
+                name
+            );
+        }
+        assert!(quantizer_from_name("not-an-algorithm", &QuantizerOptions::default()).is_none());
+    }
+
+    // Hands out a fixed pallet so the k-means refinement can be checked on its own
+    struct FixedSeeds(Vec<ColorChannel>);
+
+    impl Quantizer for FixedSeeds {
+        fn quantize_histogram(
+            &mut self,
+            _hist: &Histogram,
+            _pallet_size: u32,
+        ) -> Vec<ColorChannel> {
+            self.0.clone()
+        }
+    }
+
+    fn grey(v: u32) -> u32 {
+        v << 16 | v << 8 | v
+    }
+
+    #[test]
+    fn kmeans_moves_seeds_to_cluster_means() {
+        let hist = Histogram::new(
+            vec![grey(10), grey(20), grey(200), grey(220)],
+            vec![1, 1, 1, 1],
+        );
+        let seeds = FixedSeeds(vec![
+            ColorChannel::new_colors(0, 0, 0, 0),
+            ColorChannel::new_colors(100, 100, 100, 0),
+        ]);
+        let refined = KMeansRefine::new(Box::new(seeds), 10).quantize_histogram(&hist, 2);
+
+        let mut greys: Vec<(u8, usize)> = refined.iter().map(|c| (c.red, c.count)).collect();
+        greys.sort();
+        assert_eq!(greys, vec![(15, 2), (210, 2)]);
+    }
+
+    #[test]
+    fn octree_returns_pallet_sorted_by_population() {
+        let mut colors = Vec::new();
+        let mut counts = Vec::new();
+        for r in 0..8 {
+            for b in 0..8 {
+                colors.push((r * 32) << 16 | (b * 32));
+                counts.push(1 + r as usize);
+            }
+        }
+        let hist = Histogram::new(colors, counts);
+        let pallet = Octree::default().quantize_histogram(&hist, 16);
+
+        assert_eq!(pallet.len(), 16);
+        assert_eq!(pallet.iter().map(|c| c.count).sum::<usize>(), 8 * 36);
+        assert!(pallet.windows(2).all(|w| w[0].count >= w[1].count));
+    }
+
+    #[test]
+    fn wu_separates_clusters() {
+        let hist = Histogram::new(
+            vec![grey(10), grey(12), grey(14), grey(200), grey(210), 0xFF0000],
+            vec![10, 10, 10, 5, 5, 1],
+        );
+        let pallet = Wu::default().quantize_histogram(&hist, 3);
+
+        assert_eq!(pallet.len(), 3);
+        assert_eq!((pallet[0].red, pallet[0].count), (12, 30));
+        assert_eq!((pallet[1].red, pallet[1].count), (205, 10));
+        assert_eq!(pallet[2].count, 1);
+    }
+
+    #[test]
+    fn wu_handles_large_bright_images() {
+        // A mostly white 4K image, the squared moment sums are past i64::MAX
+        let pixels = 3840 * 2160;
+        let hist = Histogram::new(
+            vec![grey(20), grey(250), grey(255)],
+            vec![1000, pixels / 2, pixels / 2 - 1000],
+        );
+        let pallet = Wu::default().quantize_histogram(&hist, 2);
+
+        assert_eq!(pallet.len(), 2);
+        assert_eq!(pallet[0].count, pixels - 1000);
+        assert!(pallet[0].red >= 250);
+        assert_eq!((pallet[1].red, pallet[1].count), (20, 1000));
+    }
+
+    #[test]
+    fn color_spaces_round_trip() {
+        for name in COLOR_SPACES.iter() {
+            let space = ColorSpace::from_name(name).unwrap();
+            for &(r, g, b) in &[(0, 0, 0), (255, 255, 255), (200, 30, 90), (17, 140, 250)] {
+                assert_eq!(
+                    space.to_rgb(space.to_coords(r, g, b)),
+                    [r, g, b],
+                    "{}",
+                    name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn color_conversions_round_trip() {
+        type Conversion = (&'static str, fn(Color) -> [f64; 3], fn([f64; 3]) -> Color);
+        let conversions: [Conversion; 6] = [
+            ("linear", Color::to_linear, Color::from_linear),
+            ("hsl", Color::to_hsl, Color::from_hsl),
+            ("hsv", Color::to_hsv, Color::from_hsv),
+            ("lab", Color::to_lab, Color::from_lab),
+            ("oklab", Color::to_oklab, Color::from_oklab),
+            ("oklch", Color::to_oklch, Color::from_oklch),
+        ];
+        // Every corner of the sRGB cube, greys and a spread of colors in between, plus a color out of
+        // gamut that adjustments could make
+        let mut colors = Vec::new();
+        for r in (0..=255).step_by(51) {
+            for g in (0..=255).step_by(51) {
+                for b in (0..=255).step_by(85) {
+                    colors.push(Color::from_rgb8(r as u8, g as u8, b as u8));
+                }
+            }
+        }
+        colors.push(Color::from_rgb8(200, 30, 90));
+        colors.push(Color::new(1.2, -0.1, 0.5));
+
+        for &(name, to, from) in &conversions {
+            for &c in &colors {
+                let back = from(to(c));
+                for (a, b) in [c.red, c.grn, c.blu]
+                    .iter()
+                    .zip(&[back.red, back.grn, back.blu])
+                {
+                    assert!((a - b).abs() < 1e-6, "{} {:?} {:?}", name, c, back);
+                }
+                if c.red >= 0.0 && c.red <= 1.0 {
+                    assert_eq!(back.to_rgb8(), c.to_rgb8(), "{}", name);
+                }
+            }
+        }
+
+        // Known values
+        assert_eq!(Color::from_hsl([120.0, 1.0, 0.25]).to_rgb8(), [0, 128, 0]);
+        assert_eq!(Color::from_rgb8(255, 0, 0).to_hsl(), [0.0, 1.0, 0.5]);
+        assert_eq!(Color::from_rgb8(255, 0, 0).to_hsv(), [0.0, 1.0, 1.0]);
+        let grey = Color::from_rgb8(128, 128, 128);
+        assert_eq!(grey.to_hsl(), [0.0, 0.0, 128.0 / 255.0]);
+        assert_eq!(grey.to_hsv(), [0.0, 0.0, 128.0 / 255.0]);
+        assert_eq!(
+            Color::from_hsl([0.0, 0.0, 128.0 / 255.0]).to_rgb8(),
+            [128, 128, 128]
+        );
+        assert_eq!(
+            Color::from_hsv([240.0, 0.5, 1.0]).to_rgb8(),
+            [128, 128, 255]
+        );
+        let white = Color::from_rgb8(255, 255, 255).to_oklch();
+        assert!((white[0] - 1.0).abs() < 1e-6 && white[1] < 1e-6);
+        let red = Color::from_rgb8(255, 0, 0).to_lab();
+        assert!((red[0] - 53.24).abs() < 0.01 && (red[1] - 80.09).abs() < 0.01);
+    }
+
+    #[test]
+    fn ciede2000_matches_sharma_test_data() {
+        // Table 1 of Sharma, Wu and Dalal (2005), both colors and the expected difference
+        let data = [
+            ([50.0, 2.6772, -79.7751], [50.0, 0.0, -82.7485], 2.0425),
+            ([50.0, 3.1571, -77.2803], [50.0, 0.0, -82.7485], 2.8615),
+            ([50.0, 2.8361, -74.0200], [50.0, 0.0, -82.7485], 3.4412),
+            ([50.0, -1.3802, -84.2814], [50.0, 0.0, -82.7485], 1.0000),
+            ([50.0, -1.1848, -84.8006], [50.0, 0.0, -82.7485], 1.0000),
+            ([50.0, -0.9009, -85.5211], [50.0, 0.0, -82.7485], 1.0000),
+            ([50.0, 0.0, 0.0], [50.0, -1.0, 2.0], 2.3669),
+            ([50.0, -1.0, 2.0], [50.0, 0.0, 0.0], 2.3669),
+            ([50.0, 2.4900, -0.0010], [50.0, -2.4900, 0.0009], 7.1792),
+            ([50.0, 2.4900, -0.0010], [50.0, -2.4900, 0.0010], 7.1792),
+            ([50.0, 2.4900, -0.0010], [50.0, -2.4900, 0.0011], 7.2195),
+            ([50.0, 2.4900, -0.0010], [50.0, -2.4900, 0.0012], 7.2195),
+            ([50.0, -0.0010, 2.4900], [50.0, 0.0009, -2.4900], 4.8045),
+            ([50.0, -0.0010, 2.4900], [50.0, 0.0010, -2.4900], 4.8045),
+            ([50.0, -0.0010, 2.4900], [50.0, 0.0011, -2.4900], 4.7461),
+            ([50.0, 2.5, 0.0], [50.0, 0.0, -2.5], 4.3065),
+            ([50.0, 2.5, 0.0], [73.0, 25.0, -18.0], 27.1492),
+            ([50.0, 2.5, 0.0], [61.0, -5.0, 29.0], 22.8977),
+            ([50.0, 2.5, 0.0], [56.0, -27.0, -3.0], 31.9030),
+            ([50.0, 2.5, 0.0], [58.0, 24.0, 15.0], 19.4535),
+            ([50.0, 2.5, 0.0], [50.0, 3.1736, 0.5854], 1.0000),
+            ([50.0, 2.5, 0.0], [50.0, 3.2972, 0.0], 1.0000),
+            ([50.0, 2.5, 0.0], [50.0, 1.8634, 0.5757], 1.0000),
+            ([50.0, 2.5, 0.0], [50.0, 3.2592, 0.3350], 1.0000),
+            (
+                [60.2574, -34.0099, 36.2677],
+                [60.4626, -34.1751, 39.4387],
+                1.2644,
+            ),
+            (
+                [63.0109, -31.0961, -5.8663],
+                [62.8187, -29.7946, -4.0864],
+                1.2630,
+            ),
+            (
+                [61.2901, 3.7196, -5.3901],
+                [61.4292, 2.2480, -4.9620],
+                1.8731,
+            ),
+            (
+                [35.0831, -44.1164, 3.7933],
+                [35.0232, -40.0716, 1.5901],
+                1.8645,
+            ),
+            (
+                [22.7233, 20.0904, -46.6940],
+                [23.0331, 14.9730, -42.5619],
+                2.0373,
+            ),
+            (
+                [36.4612, 47.8580, 18.3852],
+                [36.2715, 50.5065, 21.2231],
+                1.4146,
+            ),
+            (
+                [90.8027, -2.0831, 1.4410],
+                [91.1528, -1.6435, 0.0447],
+                1.4441,
+            ),
+            (
+                [90.9257, -0.5406, -0.9208],
+                [88.6381, -0.8985, -0.7239],
+                1.5381,
+            ),
+            (
+                [6.7747, -0.2908, -2.4247],
+                [5.8714, -0.0985, -2.2286],
+                0.6377,
+            ),
+            (
+                [2.0776, 0.0795, -1.1350],
+                [0.9033, -0.0636, -0.5514],
+                0.9082,
+            ),
+        ];
+        for (i, (lab1, lab2, expected)) in data.iter().enumerate() {
+            let found = ciede2000(lab1, lab2);
+            assert!((found - expected).abs() < 1e-4, "pair {}: {}", i + 1, found);
+            // The formula is symmetric
+            assert!((ciede2000(lab2, lab1) - found).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn pairwise_distances_closest_first() {
+        let black = ColorChannel::new_colors(0, 0, 0, 1);
+        let white = ColorChannel::new_colors(255, 255, 255, 1);
+        let grey = ColorChannel::new_colors(250, 250, 250, 1);
+        for &metric in &[DeltaE::Cie76, DeltaE::Ciede2000, DeltaE::Oklab] {
+            let pairs = pairwise(&[black, white, grey], metric);
+            assert_eq!(pairs.len(), 3);
+            assert_eq!((pairs[0].0, pairs[0].1), (1, 2), "{:?}", metric);
+            assert_eq!((pairs[2].0, pairs[2].1), (0, 1), "{:?}", metric);
+            // Black to white is the whole lightness range for every metric
+            assert!((pairs[2].2 - 100.0).abs() < 0.01, "{:?}", metric);
+        }
+    }
+
+    #[test]
+    fn every_pixel_lands_in_histogram() {
+        // Odd sizes so a reader that skips or misaligns pixels can't line up by accident
+        let (w, h) = (37, 23);
+        let img = RgbaImage::from_fn(w, h, |x, y| Rgba([x as u8, y as u8, (x * y) as u8, 255]));
+        let pixels = rgba_pixels(&img);
+        assert_eq!(pixels.len(), (w * h) as usize);
+
+        let hist = Histogram::new_pixels(pixels, &HistogramOptions::default());
+        assert_eq!(hist.pixel_count(), (w * h) as usize);
+
+        let channels = hist.channels();
+        assert_eq!(channels.len(), (w * h) as usize);
+        for (x, y, p) in img.enumerate_pixels() {
+            let c = channels
+                .iter()
+                .find(|c| c.rgb == pack_rgb(p[0], p[1], p[2]))
+                .unwrap_or_else(|| panic!("pixel {},{} missing", x, y));
+            assert_eq!((c.red, c.grn, c.blu, c.count), (p[0], p[1], p[2], 1));
+        }
+    }
+
+    #[test]
+    fn transparent_pixels_are_not_black() {
+        let pixels = [
+            [0, 0, 0, 0],
+            [0, 0, 0, 0],
+            [0, 0, 0, 100],
+            [0, 0, 0, 100],
+            [0, 0, 0, 100],
+            [90, 0, 0, 100],
+            [200, 0, 0, 255],
+        ];
+
+        let skipped = Histogram::new_pixels(&pixels, &HistogramOptions::default()).channels();
+        assert_eq!(skipped.len(), 1);
+        assert_eq!((skipped[0].red, skipped[0].count), (200, 1));
+
+        let weighted = HistogramOptions {
+            alpha: AlphaMode::Weighted,
+            ..Default::default()
+        };
+        let hist = Histogram::new_pixels(&pixels, &weighted);
+        let counts: Vec<(u8, usize)> = hist.channels().iter().map(|c| (c.red, c.count)).collect();
+        // Counted in 255ths, a single pixel at alpha 100 is kept with less weight than an opaque one
+        assert_eq!(counts, vec![(0, 300), (90, 100), (200, 255)]);
+        assert_eq!(hist.pixel_count(), 7);
+
+        // Nothing passes the threshold, so every pixel is counted
+        let clear = [[0, 0, 0, 0], [10, 10, 10, 0]];
+        let all = Histogram::new_pixels(&clear, &HistogramOptions::default());
+        assert_eq!(all.channels().len(), 2);
+    }
+
+    #[test]
+    fn histogram_modes_agree() {
+        let img = RgbaImage::from_fn(64, 48, |x, y| Rgba([(x * 4) as u8, (y * 5) as u8, 7, 255]));
+        let build = |mode| {
+            let options = HistogramOptions {
+                mode,
+                ..Default::default()
+            };
+            Histogram::from_image(&img, &options).channels()
+        };
+
+        let sorted = build(HistogramMode::Sorted);
+        assert_eq!(build(HistogramMode::Exact), sorted);
+
+        let reduced = build(HistogramMode::Rgb555);
+        assert!(reduced.len() <= sorted.len());
+        assert_eq!(reduced.iter().map(|c| c.count).sum::<usize>(), 64 * 48);
+    }
+
+    // Compares the histogram modes on the images in res/, run with
+    // cargo test --release histogram_benchmark -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn histogram_benchmark() {
+        let mut files: Vec<_> = std::fs::read_dir("res")
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        files.sort();
+        for file in files {
+            let img = image::open(&file).unwrap().to_rgba();
+            print!("{:<40}", file.display());
+            for &(mode, threads) in &[
+                (HistogramMode::Sorted, 1),
+                (HistogramMode::Exact, 1),
+                (HistogramMode::Rgb555, 1),
+                (HistogramMode::Exact, 0),
+                (HistogramMode::Rgb555, 0),
+            ] {
+                let options = HistogramOptions {
+                    mode,
+                    threads,
+                    ..Default::default()
+                };
+                let start = Instant::now();
+                let hist = Histogram::from_image(&img, &options);
+                print!(
+                    " {:?}/{} {:>7.1}ms ({} colors)",
+                    mode,
+                    threads,
+                    start.elapsed().as_secs_f64() * 1000.0,
+                    hist.channels().len()
+                );
+            }
+            println!();
+        }
+    }
+
+    #[test]
+    fn parallel_histogram_matches_single_thread() {
+        let img = RgbaImage::from_fn(640, 480, |x, y| {
+            Rgba([(x % 251) as u8, (y % 241) as u8, ((x + y) % 7) as u8, 255])
+        });
+        for mode in &[
+            HistogramMode::Exact,
+            HistogramMode::Rgb555,
+            HistogramMode::Sorted,
+        ] {
+            let build = |threads| {
+                let options = HistogramOptions {
+                    mode: *mode,
+                    threads,
+                    ..Default::default()
+                };
+                Histogram::from_image(&img, &options).channels()
+            };
+            assert_eq!(build(1), build(4), "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn downsample_keeps_pallet_close() {
+        let img = image::open("res/snow_sunset.jpeg").unwrap().to_rgba();
+        let downsample = Downsample {
+            target: Target::MaxPixels(128 * 128),
+            ..Default::default()
+        };
+        let small = downsample.apply(&img).unwrap();
+        assert!(small.width() * small.height() <= 128 * 128);
+        // Thin images stay within the budget too, the short side can't go below a pixel
+        assert_eq!(downsample.target_size(100_000, 2), Some((128 * 128, 1)));
+        assert_eq!(downsample.target_size(3, 100_000), Some((1, 128 * 128)));
+
+        let pallet = |img: &RgbaImage| {
+            let hist = Histogram::from_image(img, &HistogramOptions::default());
+            Wu::default().quantize_histogram(&hist, 16)
+        };
+        let full = pallet(&img);
+        assert_eq!(pallet_drift(&full, &full, DeltaE::Cie76).max, 0.0);
+        assert!(pallet_drift(&pallet(&small), &full, DeltaE::Cie76).mean < 5.0);
+    }
+
+    // Makes a colorscheme with Wu from an 8x8 image drawn by pixel and reads it back. The image and
+    // the colorscheme go in the temp dir under name, so tests running at once don't share files.
+    fn theme_from_image(
+        name: &str,
+        pixel: impl Fn(u32, u32) -> Rgba<u8>,
+        options: &ThemeOptions,
+    ) -> Theme {
+        let dir = env::temp_dir();
+        let image_file = dir.join(format!("rusty_theme_{}.png", name));
+        RgbaImage::from_fn(8, 8, pixel).save(&image_file).unwrap();
+        let output_file = dir.join(format!("test_colorscheme_{}", name));
+        colors_from_image(
+            image_file.to_str().unwrap(),
+            output_file.to_str().unwrap(),
+            &mut Wu::default(),
+            options,
+        )
+        .unwrap();
+        Theme::from_xresources(&std::fs::read_to_string(&output_file).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn fewer_colors_than_pallet_size() {
+        let two_colors = |x, _| {
+            if x < 4 {
+                Rgba([200, 30, 30, 255])
+            } else {
+                Rgba([30, 30, 200, 255])
+            }
+        };
+        for &pallet_size in &[8, 16, 256] {
+            for &completion in &[Completion::None, Completion::Lightness] {
+                let options = ThemeOptions {
+                    pallet_size,
+                    completion,
+                    ..Default::default()
+                };
+                let theme = theme_from_image("two_colors", two_colors, &options);
+                let expected = match completion {
+                    Completion::None => 2,
+                    _ => pallet_size as usize,
+                };
+                assert_eq!(theme.colors.len(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn luminance_models() {
+        for name in LUMINANCE_MODELS.iter() {
+            let model = Luminance::from_name(name).unwrap();
+            assert_eq!(model.name(), *name);
+            assert!(model.of(Color::from_rgb8(0, 0, 0)).abs() < 1e-9, "{}", name);
+            assert!((model.of(Color::from_rgb8(255, 255, 255)) - 1.0).abs() < 1e-6);
+            let greys: Vec<f64> = (0..=255)
+                .step_by(15)
+                .map(|v| model.of(Color::from_rgb8(v, v, v)))
+                .collect();
+            assert!(greys.windows(2).all(|w| w[0] < w[1]), "{}", name);
+        }
+        let grey = Color::from_rgb8(128, 128, 128);
+        assert!((Luminance::Wcag.of(grey) - 0.2159).abs() < 1e-4);
+        assert!((Luminance::Lab.of(grey) - 0.5359).abs() < 1e-4);
+    }
+
+    #[test]
+    fn background_is_darkest_by_luminance() {
+        // Gamma encoded Rec.601 weights put the blue below the grey, it is brighter than it
+        let options = ThemeOptions {
+            pallet_size: 2,
+            ..Default::default()
+        };
+        let blue_grey = |x, _| {
+            if x < 4 {
+                Rgba([0, 0, 255, 255])
+            } else {
+                Rgba([40, 40, 40, 255])
+            }
+        };
+        let theme = theme_from_image("blue_grey", blue_grey, &options);
+        assert!(theme
+            .metadata
+            .contains(&(String::from("luminance"), String::from("wcag"))));
+        assert_eq!(to_hex(theme.background), "#282828");
+        assert_eq!(to_hex(theme.foreground), "#0000FF");
+    }
+
+    #[test]
+    fn contrast_matches_reference_values() {
+        let hex = |v: u8| Color::from_rgb8(v, v, v);
+        assert!((wcag_ratio(hex(0), hex(255)) - 21.0).abs() < 1e-9);
+        assert!((wcag_ratio(hex(0x77), hex(255)) - 4.48).abs() < 0.01);
+        // Examples published with the APCA-W3 reference code
+        assert!((apca_lc(hex(0x88), hex(0xff)) - 63.056).abs() < 0.001);
+        assert!((apca_lc(hex(0xff), hex(0x88)) + 68.541).abs() < 0.001);
+        assert!((apca_lc(hex(0x00), hex(0xaa)) - 58.146).abs() < 0.001);
+        assert!((apca_lc(hex(0xaa), hex(0x00)) + 56.241).abs() < 0.001);
+    }
+
+    #[test]
+    fn contrast_enforced_against_background() {
+        let background = ColorChannel::new_colors(20, 20, 24, 10);
+        let blue = ColorChannel::new_colors(20, 30, 120, 5);
+        let white = ColorChannel::new_colors(240, 240, 240, 5);
+        for &target in &[Contrast::Wcag(4.5), Contrast::Apca(60.0)] {
+            let mut pallet = vec![background, blue, white];
+            let changed = enforce(&mut pallet, 0, target);
+            assert_eq!(changed, vec![1], "{:?}", target);
+            assert_eq!(pallet[0], background);
+            assert_eq!(pallet[2], white);
+
+            let adjusted = pallet[1].color();
+            match target {
+                Contrast::Wcag(ratio) => assert!(wcag_ratio(adjusted, background.color()) >= ratio),
+                Contrast::Apca(lc) => assert!(apca_lc(adjusted, background.color()).abs() >= lc),
+            }
+            // Lighter but still the same blue
+            let before = blue.color().to_oklch();
+            let after = adjusted.to_oklch();
+            assert!(after[0] > before[0]);
+            assert!((after[2] - before[2]).abs() < 3.0, "{:?}", after);
+            assert_eq!(pallet[1].count, blue.count);
+        }
+    }
+
+    #[test]
+    fn hungarian_finds_optimal_assignment() {
+        // Greedy takes the 1 in the corner and is left with 100, the best total is 2 + 3
+        let costs = vec![vec![1.0, 2.0], vec![3.0, 100.0]];
+        assert_eq!(hungarian(&costs), vec![1, 0]);
+
+        let costs = vec![
+            vec![4.0, 1.0, 3.0],
+            vec![2.0, 0.0, 5.0],
+            vec![3.0, 2.0, 2.0],
+        ];
+        assert_eq!(hungarian(&costs), vec![1, 0, 2]);
+    }
+
+    #[test]
+    fn ansi_slots_follow_hue() {
+        let rgb = |r, g, b| ColorChannel::new_colors(r, g, b, 1);
+        let ansi = [
+            rgb(24, 22, 28),
+            rgb(190, 50, 40),
+            rgb(60, 160, 70),
+            rgb(200, 170, 60),
+            rgb(50, 80, 180),
+            rgb(170, 60, 160),
+            rgb(60, 160, 170),
+            rgb(200, 200, 195),
+        ];
+        // Most common first, nothing like the ANSI order
+        let shuffled = [6, 3, 0, 7, 1, 5, 2, 4]
+            .iter()
+            .map(|&i| ansi[i])
+            .collect::<Vec<_>>();
+        assert_eq!(assign_ansi(&shuffled), ansi.to_vec());
+
+        // Colors past the 16 ANSI slots keep their place
+        let mut many = vec![rgb(128, 128, 128); 18];
+        many[17] = rgb(255, 0, 0);
+        assert_eq!(assign_ansi(&many)[17], many[17]);
+    }
+
+    #[test]
+    fn derived_bright_colors_follow_base() {
+        let base = [
+            ColorChannel::new_colors(20, 20, 24, 30),
+            ColorChannel::new_colors(180, 40, 40, 20),
+            ColorChannel::new_colors(40, 90, 200, 10),
+            ColorChannel::new_colors(250, 250, 250, 5),
+        ];
+        let pallet = derive_bright(&base, 8, DEFAULT_BRIGHT_LIGHTNESS, DEFAULT_BRIGHT_CHROMA);
+        assert_eq!(pallet.len(), 8);
+        assert_eq!(&pallet[..4], &base[..]);
+        for (c, bright) in base.iter().zip(&pallet[4..]) {
+            let before = c.color().to_oklch();
+            let after = bright.color().to_oklch();
+            assert_eq!(bright.count, 0);
+            assert!(
+                after[0] > before[0] || before[0] > 0.95,
+                "{:?} {:?}",
+                c,
+                bright
+            );
+            if before[1] > 0.05 {
+                let diff = (after[2] - before[2]).abs();
+                assert!(diff.min(360.0 - diff) < 3.0, "{:?} {:?}", c, bright);
+            }
+        }
+        // An odd number of colors takes the extra one from the image
+        assert_eq!(Scheme::Raw.base_size(16), 16);
+        let derived = Scheme::Derived {
+            lightness: DEFAULT_BRIGHT_LIGHTNESS,
+            chroma: DEFAULT_BRIGHT_CHROMA,
+        };
+        assert_eq!(derived.base_size(16), 8);
+        assert_eq!(derived.base_size(7), 4);
+        assert_eq!(derive_bright(&base, 7, 0.1, 1.0).len(), 7);
+    }
+
+    #[test]
+    fn auto_mode_picks_light_theme_for_light_images() {
+        let mut pixels = vec![[250, 248, 240, 255]; 300];
+        pixels.extend(vec![[30, 30, 40, 255]; 100]);
+        let hist = Histogram::new_pixels(&pixels, &HistogramOptions::default());
+        assert!(hist.median_lightness() > 0.95);
+        assert_eq!(Mode::Auto.resolve(hist.median_lightness()), Mode::Light);
+        assert_eq!(Mode::Auto.resolve(0.3), Mode::Dark);
+        assert_eq!(Mode::Dark.resolve(0.9), Mode::Dark);
+
+        let options = ThemeOptions {
+            pallet_size: 3,
+            mode: Mode::Auto,
+            ..Default::default()
+        };
+        let light = |x, y| match (x, y) {
+            (0..=1, _) => Rgba([30, 30, 40, 255]),
+            (2..=3, 0..=3) => Rgba([230, 200, 60, 255]),
+            _ => Rgba([250, 248, 240, 255]),
+        };
+        let theme = theme_from_image("light", light, &options);
+        assert_eq!(to_hex(theme.background), "#FAF8F0");
+        assert_eq!(to_hex(theme.foreground), "#1E1E28");
+        // The yellow is darkened until it can be read on the light background
+        let yellow = *theme
+            .colors
+            .iter()
+            .find(|c| c.red as i32 > c.blu as i32 + 40)
+            .unwrap();
+        let background = ColorChannel::new_colors(250, 248, 240, 0);
+        assert!(wcag_ratio(yellow.color(), background.color()) >= 4.5);
+    }
+
+    #[test]
+    fn completion_fills_monochrome_pallet() {
+        let grey = [ColorChannel::new_colors(128, 128, 128, 64)];
+        for &strategy in &[
+            Completion::Interpolate,
+            Completion::Lightness,
+            Completion::Hue,
+        ] {
+            let pallet = complete_pallet(
+                &grey,
+                16,
+                strategy,
+                DEFAULT_DEDUPE_THRESHOLD,
+                DeltaE::default(),
+            );
+            assert_eq!(pallet.len(), 16);
+            assert_eq!(pallet[0], grey[0]);
+            assert!(pallet[1..].iter().all(|c| c.count == 0));
+            assert_eq!(
+                remove_duplicates(&pallet, DEFAULT_DEDUPE_THRESHOLD, DeltaE::default()).len(),
+                16,
+                "{:?}",
+                strategy
+            );
+        }
+        let pallet = complete_pallet(
+            &grey,
+            16,
+            Completion::None,
+            DEFAULT_DEDUPE_THRESHOLD,
+            DeltaE::default(),
+        );
+        assert_eq!(pallet.len(), 1);
+    }
+
+    #[test]
+    fn duplicates_merge_and_quantizer_is_asked_again() {
+        let grey = ColorChannel::new_colors(128, 128, 128, 64);
+        let near = ColorChannel::new_colors(129, 128, 128, 4);
+        let merged = remove_duplicates(&[near, grey], DEFAULT_DEDUPE_THRESHOLD, DeltaE::default());
+        assert_eq!(merged, vec![ColorChannel::new_colors(128, 128, 128, 68)]);
+        assert_eq!(
+            remove_duplicates(&[near, grey], 0.0, DeltaE::default()).len(),
+            2
+        );
+
+        // Two big near identical greens and a small but distinct red and blue, asking for three
+        // colors gives both greens, the quantizer has to be asked again to find the red
+        let mut pixels = Vec::new();
+        pixels.extend(vec![[40, 160, 40, 255]; 400]);
+        pixels.extend(vec![[41, 161, 40, 255]; 400]);
+        pixels.extend(vec![[200, 20, 20, 255]; 20]);
+        pixels.extend(vec![[20, 20, 200, 255]; 10]);
+        let hist = Histogram::new_pixels(&pixels, &HistogramOptions::default());
+        let pallet = distinct_pallet(
+            &mut Wu::default(),
+            &hist,
+            3,
+            DEFAULT_DEDUPE_THRESHOLD,
+            DeltaE::default(),
+        );
+        assert_eq!(pallet.len(), 3);
+        assert_eq!(pallet[0].count, 800);
+        assert!(pallet.iter().any(|c| c.red == 200));
+        assert!(pallet.iter().any(|c| c.blu == 200));
+    }
+
+    #[test]
+    fn adjustments_change_pallet_in_oklch() {
+        let red = ColorChannel::new_colors(200, 60, 50, 10);
+        let grey = ColorChannel::new_colors(100, 100, 100, 5);
+        let lch = |c: &ColorChannel| c.color().to_oklch();
+
+        let mut pallet = vec![red, grey];
+        adjust_pallet(&mut pallet, &[Adjustment::Saturate(-100.0)]);
+        assert!(lch(&pallet[0])[1] < 0.001);
+        assert_eq!(pallet[0].count, 10);
+
+        let mut pallet = vec![red, grey];
+        adjust_pallet(&mut pallet, &[Adjustment::Lighten(10.0)]);
+        assert!((lch(&pallet[1])[0] - lch(&grey)[0] - 0.1).abs() < 0.01);
+        assert!((lch(&pallet[0])[2] - lch(&red)[2]).abs() < 1.0);
+
+        let mut pallet = vec![red, grey];
+        adjust_pallet(&mut pallet, &[Adjustment::Contrast(50.0)]);
+        let spread = |p: &[ColorChannel]| (lch(&p[0])[0] - lch(&p[1])[0]).abs();
+        assert!(spread(&pallet) > spread(&[red, grey]) * 1.4);
+
+        let mut pallet = vec![red];
+        adjust_pallet(&mut pallet, &[Adjustment::HueRotate(180.0)]);
+        assert!(pallet[0].blu > pallet[0].red);
+
+        // Warming turns a grey orange, cooling turns it blue
+        let mut pallet = vec![grey];
+        adjust_pallet(&mut pallet, &[Adjustment::Temperature(100.0)]);
+        assert!(pallet[0].red > pallet[0].blu);
+        let mut pallet = vec![grey];
+        adjust_pallet(&mut pallet, &[Adjustment::Temperature(-100.0)]);
+        assert!(pallet[0].blu > pallet[0].red);
+
+        assert_eq!(
+            parse_adjustments("# warmer\n\nsaturate 20\n  darken 5\nhue-rotate -15\n"),
+            Ok(vec![
+                Adjustment::Saturate(20.0),
+                Adjustment::Lighten(-5.0),
+                Adjustment::HueRotate(-15.0),
+            ])
+        );
+        assert!(parse_adjustments("saturate\n").is_err());
+        assert!(parse_adjustments("sharpen 3\n").is_err());
+        assert!(parse_adjustments("lighten NaN\n").is_err());
+        assert!(parse_adjustments("saturate inf\n").is_err());
+
+        // Options run in the order they are given, --min-contrast isn't --contrast
+        let args: Vec<String> = [
+            "rusty-theme",
+            "--temperature=-30",
+            "--min-contrast",
+            "4.5",
+            "--saturate",
+            "20",
+            "--contrast",
+            "10",
+        ]
+        .iter()
+        .map(|a| a.to_string())
+        .collect();
+        assert_eq!(
+            adjustment_order(&args),
+            vec!["temperature", "saturate", "contrast"]
+        );
+    }
+
+    #[test]
+    fn cvd_collisions_are_moved_apart() {
+        // Greys look the same with every deficiency, rows of the matrices add up to 1
+        let grey = Color::from_rgb8(120, 120, 120);
+        for &deficiency in DEFICIENCIES.iter() {
+            assert_eq!(deficiency.simulate(grey).to_rgb8(), [120, 120, 120]);
+        }
+
+        // A red and a green of about the same lightness are the classic red-green collision
+        let mut pallet = vec![
+            ColorChannel::new_colors(20, 20, 20, 50),
+            ColorChannel::new_colors(190, 80, 60, 30),
+            ColorChannel::new_colors(110, 140, 50, 20),
+        ];
+        let metric = DeltaE::default();
+        let before = collisions(&pallet, Deficiency::Deutan, metric, DEFAULT_CVD_THRESHOLD);
+        assert_eq!(
+            before.iter().map(|&(i, j, _)| (i, j)).collect::<Vec<_>>(),
+            vec![(1, 2)]
+        );
+
+        let red = pallet[1];
+        let changed = adapt(
+            &mut pallet,
+            &[Deficiency::Deutan],
+            metric,
+            DEFAULT_CVD_THRESHOLD,
+        );
+        // The less common green is moved, the red stays as it was
+        assert_eq!(changed, vec![2]);
+        assert_eq!(pallet[1], red);
+        assert!(collisions(&pallet, Deficiency::Deutan, metric, DEFAULT_CVD_THRESHOLD).is_empty());
+    }
+
+    #[test]
+    fn templates_fill_placeholders() {
+        let colors = vec![
+            (
+                String::from("background"),
+                ColorChannel::new_colors(8, 16, 32, 0),
+            ),
+            (
+                String::from("color1"),
+                ColorChannel::new_colors(200, 60, 50, 0),
+            ),
+        ];
+        let rendered = render(
+            "bg = \"{background}\"\nbg.strip = {background.strip}; rgb({color1.rgb})\n\
+             {{background}} { color: {color9} }\ngreen = {color1.red}/{color1.green}\n",
+            &colors,
+        );
+        assert_eq!(
+            rendered.unwrap(),
+            "bg = \"#081020\"\nbg.strip = 081020; rgb(200,60,50)\n\
+             {background} { color: {color9} }\ngreen = 200/60\n"
+        );
+
+        let lighter = render("{color1.lighten(10).strip}", &colors).unwrap();
+        let lighter = u32::from_str_radix(&lighter, 16).unwrap();
+        let lighter = ColorChannel::new_colors(
+            (lighter >> 16) as u8,
+            (lighter >> 8) as u8,
+            lighter as u8,
+            0,
+        );
+        let l = lighter.color().to_oklch()[0] - colors[1].1.color().to_oklch()[0];
+        assert!((l - 0.1).abs() < 0.01);
+        assert_eq!(render("{color1.darken(100)}", &colors).unwrap(), "#000000");
+
+        assert_eq!(
+            render("ok\n{color1.blur(2)}", &colors),
+            Err(String::from(
+                "line 2: {color1.blur(2)}: unknown format or adjustment .blur(2)"
+            ))
+        );
+        assert_eq!(
+            render("hsl({color1.hsl}) {color1.hsv}", &colors).unwrap(),
+            "hsl(4,60%,49%) 4,75%,78%"
+        );
+        assert!(render("{color1.rgb.strip}", &colors).is_err());
+        assert!(render("{color1.lighten(x)}", &colors).is_err());
+    }
+
+    #[test]
+    fn theme_written_and_read_in_canonical_order() {
+        let colors = vec![
+            ColorChannel::new_colors(5, 10, 15, 0),
+            ColorChannel::new_colors(200, 60, 50, 0),
+            ColorChannel::new_colors(250, 248, 240, 0),
+        ];
+        let mut theme = Theme::new(colors, 0, 2);
+        theme.metadata = vec![(String::from("image"), String::from("wall.png"))];
+        let text = theme.to_xresources();
+        // Small channels are padded to two digits
+        assert_eq!(
+            text,
+            "! Colorscheme made by rusty-theme\n\
+             ! image: wall.png\n\
+             *background: #050A0F\n\
+             *foreground: #FAF8F0\n\
+             *cursorColor: #FAF8F0\n\
+             *color0: #050A0F\n\
+             *color1: #C83C32\n\
+             *color2: #FAF8F0\n"
+        );
+        assert_eq!(Theme::from_xresources(&text), Ok(theme.clone()));
+
+        // Other Xresources files, in any order and with other resources, read the same way
+        let xresources = "URxvt.font: xft:Mono\n*.color2: #faf8f0\n*.color1:  #C83C32\n\
+                          *color0: #050A0F\n*background: #050A0F\n*foreground: #FAF8F0\n";
+        let read = Theme::from_xresources(xresources).unwrap();
+        assert_eq!(read.colors, theme.colors);
+        assert_eq!(read.cursor, theme.foreground);
+        assert_eq!(
+            Theme::from_xresources(
+                "*background: #000000\n*foreground: #FFFFFF\n*color1: #FF0000\n"
+            ),
+            Err(String::from("no *color0"))
+        );
+        // Commands that only look at colors read whatever slots are there
+        assert_eq!(
+            read_slots("*color1: #FF0000\nURxvt.font: xft:Mono\n*background: #000000\n"),
+            vec![
+                (
+                    String::from("background"),
+                    ColorChannel::new_colors(0, 0, 0, 0)
+                ),
+                (
+                    String::from("color1"),
+                    ColorChannel::new_colors(255, 0, 0, 0)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn files_named_like_colors_are_read_as_files() {
+        let file = env::temp_dir().join("cafe00");
+        let theme = Theme::new(vec![ColorChannel::new_colors(1, 2, 3, 0)], 0, 0);
+        std::fs::write(&file, theme.to_xresources()).unwrap();
+        let slots = read_colors(file.to_str().unwrap()).unwrap();
+        assert_eq!(slots, theme.slots());
+        std::fs::remove_file(&file).unwrap();
+
+        let color = read_colors("cafe00").unwrap();
+        assert_eq!(color[0].1, ColorChannel::new_colors(0xCA, 0xFE, 0x00, 0));
+        let red = ColorChannel::new_colors(255, 0, 0, 0);
+        assert_eq!(read_colors("hsl(0, 100%, 50%)").unwrap()[0].1, red);
+        assert_eq!(read_colors("hsv(360,100,100)").unwrap()[0].1, red);
+        assert!(read_colors("hsl(0, 100%)").is_err());
+    }
+
+    #[test]
+    fn split_strategies_pick_expected_bucket() {
+        // Median cut on reds only, asked for 3 colors it splits once and then picks one of the two
+        // halves to split again. The half it leaves alone is still one color of the pallet.
+        let pallet = |reds: &[(u8, usize)], split: &str| {
+            let hist = Histogram::new(
+                reds.iter().map(|&(r, _)| pack_rgb(r, 0, 0)).collect(),
+                reds.iter().map(|&(_, n)| n).collect(),
+            );
+            let options = QuantizerOptions {
+                split: SplitStrategy::from_name(split).unwrap(),
+                ..Default::default()
+            };
+            MedianCut::new(&options)
+                .quantize_histogram(&hist, 3)
+                .iter()
+                .map(|c| (c.red, c.count))
+                .collect::<Vec<_>>()
+        };
+
+        // A tight, crowded half and a wide half with a few pixels. Level splits the first half, the
+        // others the wide half as it has both the larger volume and variance.
+        let reds = [
+            (0, 1000),
+            (1, 1000),
+            (2, 1000),
+            (3, 1000),
+            (100, 3000),
+            (150, 1),
+            (200, 1),
+            (250, 1),
+            (255, 1),
+        ];
+        assert!(pallet(&reds, "level").contains(&(100, 3003)));
+        assert!(pallet(&reds, "volume").contains(&(1, 3000)));
+        assert!(pallet(&reds, "variance").contains(&(1, 3000)));
+
+        // The first half reaches from a few dark pixels to one bright cluster, the second is wider
+        // with more pixels but they sit closer to their mean. Volume picks the second half, variance
+        // the first.
+        let reds = [
+            (0, 10),
+            (2, 10),
+            (4, 10),
+            (6, 10),
+            (100, 1000),
+            (120, 1000),
+            (140, 1000),
+            (160, 1000),
+            (200, 1),
+            (250, 1),
+            (255, 1),
+        ];
+        assert!(pallet(&reds, "volume").contains(&(96, 1040)));
+        assert!(pallet(&reds, "variance").contains(&(150, 2002)));
+    }
+
+    #[test]
+    fn auto_picks_algorithm_from_full_image() {
+        // The histogram of a downsampled wallpaper and of the wallpaper itself, same colors with far
+        // fewer pixels
+        let colors: Vec<u32> = (0..64u32)
+            .map(|i| pack_rgb((i * 4) as u8, (255 - i * 4) as u8, (i * 37 % 256) as u8))
+            .collect();
+        let small = Histogram::new(colors.clone(), vec![10; 64]);
+        let full = Histogram::new(colors, vec![LARGE_IMAGE_PIXELS / 32; 64]);
+        assert!(full.pixel_count() > LARGE_IMAGE_PIXELS);
+
+        let options = QuantizerOptions::default();
+        let wu = |hist: &Histogram| Wu::default().quantize_histogram(hist, 4);
+        let median_cut = MedianCut::new(&options).quantize_histogram(&small, 4);
+        assert_ne!(wu(&small), median_cut);
+
+        // On its own the small histogram looks like a small image
+        let mut auto = quantizer_from_name("auto", &options).unwrap();
+        assert_eq!(auto.quantize_histogram(&small, 4), median_cut);
+
+        // Told the size of the full image both histograms get Wu, refined or not
+        auto.set_image_pixels(full.pixel_count());
+        assert_eq!(auto.quantize_histogram(&small, 4), wu(&small));
+        assert_eq!(auto.quantize_histogram(&full, 4), wu(&full));
+        let mut refined = KMeansRefine::new(quantizer_from_name("auto", &options).unwrap(), 0);
+        refined.set_image_pixels(full.pixel_count());
+        assert_eq!(refined.quantize_histogram(&small, 4), wu(&small));
+    }
+
+    #[test]
+    fn unreadable_images_are_errors() {
+        let file = env::temp_dir().join("rusty_theme_not_an_image.png");
+        std::fs::write(&file, "! not an image\n").unwrap();
+        let output = env::temp_dir().join("rusty_theme_not_an_image");
+        let result = colors_from_image(
+            file.to_str().unwrap(),
+            output.to_str().unwrap(),
+            &mut Wu::default(),
+            &ThemeOptions::default(),
+        );
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        assert!(!output.exists());
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn median_cut_splits_in_its_color_space() {
+        // An even grey ramp. Split in rgb the pallet is spread evenly over the channel values, split
+        // in a perceptual space it is spread evenly over lightness, which gives the darks more shades.
+        let hist = Histogram::new(
+            (0..=255).map(|v| pack_rgb(v, v, v)).collect(),
+            vec![10; 256],
+        );
+        let darks = |space: &str| {
+            let options = QuantizerOptions {
+                space: ColorSpace::from_name(space).unwrap(),
+                ..Default::default()
+            };
+            MedianCut::new(&options)
+                .quantize_histogram(&hist, 6)
+                .iter()
+                .filter(|c| c.red < 128)
+                .count()
+        };
+        assert_eq!(darks("rgb"), 3);
+        assert_eq!(darks("lab"), 4);
+        assert_eq!(darks("oklab"), 4);
+    }
 }
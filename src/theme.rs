@@ -0,0 +1,181 @@
+// A colorscheme: the named slots programs read from Xresources and a few comments saying where the
+// colors came from. Colorscheme files are written and read only through Theme, always in the same
+// order: metadata, *background, *foreground, *cursorColor, then *color0 up.
+use crate::color::Color;
+use crate::q_image::ColorChannel;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Theme {
+    pub background: ColorChannel,
+    pub foreground: ColorChannel,
+    pub cursor: ColorChannel,
+    // *color0 to *colorN
+    pub colors: Vec<ColorChannel>,
+    // Written as "! key: value" comments above the colors, like the image the colors came from
+    pub metadata: Vec<(String, String)>,
+}
+
+// First line of every colorscheme file the tool writes
+const HEADER: &str = "! Colorscheme made by rusty-theme";
+
+impl Theme {
+    // A theme using two of its colors as background and foreground, the cursor follows the text
+    pub fn new(colors: Vec<ColorChannel>, background: usize, foreground: usize) -> Theme {
+        Theme {
+            background: colors[background],
+            foreground: colors[foreground],
+            cursor: colors[foreground],
+            colors,
+            metadata: Vec::new(),
+        }
+    }
+
+    // Every slot with its Xresources name, in the order they are written
+    pub fn slots(&self) -> Vec<(String, ColorChannel)> {
+        let mut slots = vec![
+            (String::from("background"), self.background),
+            (String::from("foreground"), self.foreground),
+            (String::from("cursorColor"), self.cursor),
+        ];
+        for (i, &color) in self.colors.iter().enumerate() {
+            slots.push((format!("color{}", i), color));
+        }
+        slots
+    }
+
+    pub fn to_xresources(&self) -> String {
+        let mut text = format!("{}\n", HEADER);
+        for (key, value) in &self.metadata {
+            text.push_str(&format!("! {}: {}\n", key, value));
+        }
+        for (name, color) in self.slots() {
+            text.push_str(&format!("*{}: {}\n", name, to_hex(color)));
+        }
+        text
+    }
+
+    // Reads a colorscheme file or any Xresources file, see read_slots(). The cursor is the foreground
+    // unless *cursorColor is given, the others have to be there.
+    pub fn from_xresources(text: &str) -> Result<Theme, String> {
+        let mut metadata = Vec::new();
+        let slots = parse(text, &mut metadata);
+        let slot = |name: &str| slots.iter().find(|(n, _)| n == name).map(|&(_, c)| c);
+
+        let background = slot("background").ok_or("no *background")?;
+        let foreground = slot("foreground").ok_or("no *foreground")?;
+        let mut colors = Vec::new();
+        for (name, color) in slots.iter().filter(|(n, _)| n.starts_with("color")) {
+            if *name != format!("color{}", colors.len()) {
+                return Err(format!("no *color{}", colors.len()));
+            }
+            colors.push(*color);
+        }
+        if colors.is_empty() {
+            return Err(String::from("no *color0"));
+        }
+        Ok(Theme {
+            background,
+            foreground,
+            cursor: slot("cursorColor").unwrap_or(foreground),
+            colors,
+            metadata,
+        })
+    }
+}
+
+// Whatever slots an Xresources file has, in the order a Theme writes them, for commands that look
+// at colors without needing a whole theme. Resources are matched on the last part of their name, so
+// *color1, *.color1 and URxvt.color1 are all color1, and the last one given is used. Resources that
+// aren't slots or aren't #RRGGBB colors are skipped.
+pub fn read_slots(text: &str) -> Vec<(String, ColorChannel)> {
+    parse(text, &mut Vec::new())
+}
+
+// The slots of an Xresources file, its "! key: value" comments are added to metadata
+fn parse(text: &str, metadata: &mut Vec<(String, String)>) -> Vec<(String, ColorChannel)> {
+    let mut slots: Vec<(String, ColorChannel)> = Vec::new();
+
+    for line in text.lines().map(str::trim) {
+        if let Some(comment) = line.strip_prefix('!') {
+            if let Some((key, value)) = comment.split_once(':') {
+                metadata.push((key.trim().to_string(), value.trim().to_string()));
+            }
+            continue;
+        }
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => continue,
+        };
+        let color = match parse_hex(value) {
+            Some(color) => color,
+            None => continue,
+        };
+        let name = name.rsplit(['*', '.']).next().unwrap();
+        if slot_order(name).is_none() {
+            continue;
+        }
+        match slots.iter_mut().find(|(n, _)| n == name) {
+            Some(slot) => slot.1 = color,
+            None => slots.push((name.to_string(), color)),
+        }
+    }
+    slots.sort_by_key(|(name, _)| slot_order(name));
+    slots
+}
+
+// Where a slot is written, None for resources that aren't slots
+fn slot_order(name: &str) -> Option<usize> {
+    match name {
+        "background" => Some(0),
+        "foreground" => Some(1),
+        "cursorColor" => Some(2),
+        _ => name
+            .strip_prefix("color")
+            .and_then(|n| n.parse::<usize>().ok())
+            .map(|n| n + 3),
+    }
+}
+
+pub fn to_hex(color: ColorChannel) -> String {
+    format!("#{:02X}{:02X}{:02X}", color.red, color.grn, color.blu)
+}
+
+// #RRGGBB or RRGGBB in either case
+pub fn parse_hex(hex: &str) -> Option<ColorChannel> {
+    let hex = hex.trim_start_matches('#');
+    if hex.len() != 6 {
+        return None;
+    }
+    let rgb = u32::from_str_radix(hex, 16).ok()?;
+    Some(ColorChannel::new_colors(
+        (rgb >> 16) as u8,
+        (rgb >> 8) as u8,
+        rgb as u8,
+        0,
+    ))
+}
+
+// A color given on the command line, hex like parse_hex() or hsl(h, s%, l%) and hsv(h, s%, v%) with
+// the hue in degrees, the percent signs are optional
+pub fn parse_color(text: &str) -> Option<ColorChannel> {
+    if let Some(color) = parse_hex(text) {
+        return Some(color);
+    }
+    let (space, args) = text.trim().strip_suffix(')')?.split_once('(')?;
+    let values = args
+        .split(',')
+        .map(|v| v.trim().trim_end_matches('%').parse::<f64>().ok())
+        .collect::<Option<Vec<f64>>>()?;
+    let values = match values[..] {
+        [h, s, l_or_v] if [h, s, l_or_v].iter().all(|v| v.is_finite()) => {
+            [h, s / 100.0, l_or_v / 100.0]
+        }
+        _ => return None,
+    };
+    let color = match space.trim() {
+        "hsl" => Color::from_hsl(values),
+        "hsv" => Color::from_hsv(values),
+        _ => return None,
+    };
+    Some(ColorChannel::from_color(color, 0))
+}